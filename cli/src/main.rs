@@ -2,16 +2,25 @@
 #![deny(clippy::mem_forget)]
 use clap::{AppSettings, Parser};
 use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use glob::glob;
+use log::debug;
 use qubes_converter_client;
-use qubes_converter_client::{convert_all_files, list_ocr_langs, ConvertEvent, ConvertParameters};
+use qubes_converter_client::{
+    check_target, convert_all_files, list_ocr_langs, parse_page_ranges, read_provenance,
+    AudioFormat, Config, ConvertEvent, ConvertParameters, ImageFormat, Isolation, OutputProfile,
+    PageCompression, PageRange, PixelFormat, Resolution, DISPOSABLE_TARGET,
+};
 use qubes_converter_common;
 use std::{
+    collections::HashMap,
     convert::TryInto,
-    io,
+    fs,
+    io::{self, Write},
+    process,
     sync::mpsc::{self, Receiver},
     thread,
 };
@@ -22,6 +31,9 @@ use tui::{
     widgets::{Block, Borders, Gauge},
     Terminal,
 };
+// One value of the command line, clap would take a `Vec` for a repeated option.
+type PageRanges = Vec<PageRange>;
+
 #[derive(Parser)]
 #[clap(version, about, author)]
 #[clap(setting = AppSettings::ArgRequiredElseHelp)]
@@ -41,6 +53,34 @@ struct Opts {
     #[clap(short, long)]
     default_password: Option<String>,
 
+    #[clap(
+        short = 'p',
+        long = "password",
+        help = "Password tried on every protected file. Can be repeated, passwords are tried in order"
+    )]
+    passwords: Vec<String>,
+
+    #[clap(
+        long,
+        help = "File containing passwords to try on every protected file, one per line"
+    )]
+    password_file: Option<String>,
+
+    #[clap(
+        long = "file-password",
+        value_name = "FILE=PASSWORD",
+        parse(try_from_str = parse_file_password),
+        help = "Password of one specific file, tried before the others. Can be repeated"
+    )]
+    file_passwords: Vec<(String, String)>,
+
+    #[clap(
+        long,
+        default_value = "3",
+        help = "How many times a password is asked for a protected file before giving up on it"
+    )]
+    max_password_attempts: u8,
+
     #[clap(
         short,
         long,
@@ -51,8 +91,149 @@ struct Opts {
     #[clap(short, long)]
     list_ocr_langs: bool,
 
+    #[clap(
+        long,
+        help = "Print where the given trusted files come from instead of converting them"
+    )]
+    inspect: bool,
+
     #[clap(short, long, default_value = "1")]
     max_tesseract_process: u8,
+
+    #[clap(
+        long,
+        default_value = "wav",
+        possible_values = ["wav", "flac"],
+        help = "Format of the trusted audio files. 'flac' requires the flac encoder"
+    )]
+    audio_format: AudioFormat,
+
+    #[clap(
+        long,
+        default_value = "png",
+        help = "Format of trusted still images: png, jpeg, tiff, webp, webp-lossy or avif. The quality of lossy ones can follow, like 'jpeg:75'. 'webp-lossy' requires cwebp, 'avif' requires avifenc. Only PNG records the provenance"
+    )]
+    image_format: ImageFormat,
+
+    #[clap(
+        long = "file-image-format",
+        value_name = "FILE=FORMAT",
+        parse(try_from_str = parse_file_image_format),
+        help = "Format of one specific still image, like --image-format. Can be repeated"
+    )]
+    file_image_formats: Vec<(String, ImageFormat)>,
+
+    #[clap(long, help = "Drop the sound of videos")]
+    no_video_audio: bool,
+
+    #[clap(
+        long,
+        help = "Render HTML and Markdown documents as one long PNG instead of a paginated PDF"
+    )]
+    html_single_page: bool,
+
+    #[clap(
+        long,
+        default_value = "print",
+        possible_values = ["screen", "print", "archival"],
+        help = "Resolution documents are rendered at: 75, 150 or 300 dpi"
+    )]
+    resolution: Resolution,
+
+    #[clap(
+        long,
+        parse(try_from_str = parse_page_ranges),
+        help = "Only convert these pages of paginated documents, like '1-10,45,100-'"
+    )]
+    pages: Option<PageRanges>,
+
+    #[clap(
+        long,
+        default_value = "rgba",
+        possible_values = ["rgba", "rgb", "gray", "bilevel"],
+        help = "Pixels of the trusted pages. 'gray' and 'bilevel' (black and white) are much smaller"
+    )]
+    pixel_format: PixelFormat,
+
+    #[clap(
+        long,
+        parse(try_from_str = parse_jpeg_quality),
+        help = "Store the pages of trusted PDFs as JPEG of this quality, from 1 to 100. Much smaller for photos, but lossy"
+    )]
+    jpeg_quality: Option<u8>,
+
+    #[clap(
+        long,
+        default_value = "standard",
+        possible_values = ["standard", "pdfa-2b"],
+        help = "Make trusted PDFs PDF/A-2b for archival"
+    )]
+    output_profile: OutputProfile,
+
+    #[clap(
+        long,
+        validator = check_target,
+        help = "Qube converting the files: '@dispvm', '@dispvm:<disposable template>' or a qube name. Defaults to the 'target' of the configuration file, or '@dispvm'"
+    )]
+    target: Option<String>,
+
+    #[clap(
+        long = "extension-target",
+        value_name = "EXTENSION=TARGET",
+        parse(try_from_str = parse_extension_target),
+        help = "Qube converting the files with this extension, like 'pdf=@dispvm:pdf-dvm'. Can be repeated"
+    )]
+    extension_targets: Vec<(String, String)>,
+
+    #[clap(
+        long,
+        default_value = "1",
+        parse(try_from_str = parse_parallel_servers),
+        help = "How many disposable qubes convert files at the same time, each one on its share of the files. Every qube needs its own memory"
+    )]
+    parallel_servers: u8,
+
+    #[clap(
+        long,
+        default_value = "batch",
+        possible_values = ["batch", "directory", "file"],
+        help = "Which files share a disposable qube: all of them, consecutive files of the same directory, or none. A malicious file can tamper with the files converted after it in its qube, but every qube takes seconds to start"
+    )]
+    isolation: Isolation,
+}
+fn parse_parallel_servers(value: &str) -> Result<u8, String> {
+    match value.parse() {
+        Ok(parallel_servers @ 1..) => Ok(parallel_servers),
+        _ => Err(format!("Invalid number of disposable qubes: {}", value)),
+    }
+}
+fn parse_file_password(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(file, password)| (file.to_string(), password.to_string()))
+        .ok_or_else(|| "Expected FILE=PASSWORD".to_string())
+}
+fn parse_file_image_format(value: &str) -> Result<(String, ImageFormat), String> {
+    let (file, image_format) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected FILE=FORMAT, got {}", value))?;
+    Ok((file.to_string(), image_format.parse()?))
+}
+fn parse_extension_target(value: &str) -> Result<(String, String), String> {
+    let (extension, target) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected EXTENSION=TARGET, got {}", value))?;
+    check_target(target)?;
+    Ok((
+        extension.trim_start_matches('.').to_lowercase(),
+        target.to_string(),
+    ))
+}
+fn parse_jpeg_quality(value: &str) -> Result<u8, String> {
+    match value.parse() {
+        Ok(quality @ 1..=100) => Ok(quality),
+        _ => Err(format!("Invalid JPEG quality: {}", value)),
+    }
 }
 struct FancyTuiData {
     filename: String,
@@ -74,6 +255,38 @@ fn once_correct_file_found(
         }
     }
 }
+/// Read a password from the terminal without echoing it. Escape, Ctrl-C or an empty password
+/// give up on the file.
+fn prompt_password(file: &str, attempt: u8) -> Option<String> {
+    eprint!(
+        "{} is protected by a password (attempt {}), leave empty to skip it: ",
+        file, attempt
+    );
+    io::stderr().flush().ok()?;
+    enable_raw_mode().ok()?;
+    let mut password = String::new();
+    let answer = loop {
+        match event::read() {
+            Ok(Event::Key(KeyEvent {
+                code, modifiers, ..
+            })) => match code {
+                KeyCode::Enter => break Some(password),
+                KeyCode::Esc => break None,
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => break None,
+                KeyCode::Backspace => {
+                    password.pop();
+                }
+                KeyCode::Char(c) => password.push(c),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(_) => break None,
+        }
+    };
+    let _ = disable_raw_mode();
+    eprintln!();
+    answer.filter(|password| !password.is_empty())
+}
 fn fancy_ui_main_loop(
     receiver_convert_events: Receiver<ConvertEvent>,
     all_files: &mut Vec<String>,
@@ -95,6 +308,7 @@ fn fancy_ui_main_loop(
                 output_type,
                 number_pages,
                 file,
+                ..
             } => once_correct_file_found(&file, &mut tui_data, &mut |_, data| {
                 data.output_type = Some(output_type);
                 data.number_pages = number_pages;
@@ -119,6 +333,18 @@ fn fancy_ui_main_loop(
                 });
                 eprintln!("{}: Failure, {}", file, message);
             }
+            ConvertEvent::PasswordRequired {
+                file,
+                attempt,
+                reply,
+            } => {
+                // The prompt needs the normal screen, the gauges are redrawn right after.
+                execute!(terminal.backend_mut(), LeaveAlternateScreen).unwrap();
+                let _ = reply.send(prompt_password(&file, attempt));
+                enable_raw_mode().unwrap();
+                execute!(terminal.backend_mut(), EnterAlternateScreen).unwrap();
+                terminal.clear().unwrap();
+            }
         }
         terminal
             .draw(|f| {
@@ -181,12 +407,17 @@ fn non_fancy_ui(receiver_convert_events: Receiver<ConvertEvent>, all_files: &mut
             ConvertEvent::FileInfo {
                 output_type,
                 number_pages,
+                estimated_size,
                 file,
             } => println!(
-                "{}: {} pages, output will be {}",
+                "{}: {} pages, output will be {}{}",
                 file,
                 number_pages,
-                output_type.extension()
+                output_type.extension(),
+                estimated_size.map_or_else(String::new, |size| format!(
+                    " of about {} MB",
+                    (size + 500_000) / 1_000_000
+                ))
             ),
             ConvertEvent::PageConverted { file, page } => {
                 println!("{}: converted page n\u{b0}{}", file, page);
@@ -195,7 +426,14 @@ fn non_fancy_ui(receiver_convert_events: Receiver<ConvertEvent>, all_files: &mut
                 all_files.retain(|x| *x != file);
                 println!("converted file {}", file);
             }
-            ConvertEvent::Failure { file: _, message } => eprintln!("{}", message),
+            ConvertEvent::Failure { file, message } => eprintln!("{}: {}", file, message),
+            ConvertEvent::PasswordRequired {
+                file,
+                attempt,
+                reply,
+            } => {
+                let _ = reply.send(prompt_password(&file, attempt));
+            }
         }
     }
 }
@@ -203,10 +441,14 @@ fn main() {
     env_logger::init();
     let opts: Opts = Opts::parse();
     if opts.list_ocr_langs {
-        let langs = list_ocr_langs().unwrap();
-        println!("List of language supported by your tesseract installation: ");
-        for lang in langs {
-            println!("{}", lang);
+        match list_ocr_langs() {
+            Ok(langs) => {
+                println!("List of language supported by your tesseract installation: ");
+                for lang in langs {
+                    println!("{}", lang);
+                }
+            }
+            Err(e) => eprintln!("{}", e),
         }
         return;
     }
@@ -226,18 +468,86 @@ fn main() {
         }
     }
     all_files.dedup();
+    if opts.inspect {
+        for file in &all_files {
+            match read_provenance(file) {
+                Ok(Some(provenance)) => print!("{}:\n{}", file, provenance),
+                Ok(None) => println!("{}: no provenance recorded", file),
+                Err(e) => eprintln!("{}: {}", file, e),
+            }
+        }
+        return;
+    }
+    let mut passwords: Vec<String> = opts
+        .default_password
+        .into_iter()
+        .chain(opts.passwords)
+        .collect();
+    if let Some(password_file) = opts.password_file {
+        let content = match fs::read_to_string(&password_file) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Unable to read the password file {}: {}", password_file, e);
+                process::exit(1);
+            }
+        };
+        passwords.extend(
+            content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(str::to_string),
+        );
+    }
+    let file_passwords: HashMap<String, String> = opts.file_passwords.into_iter().collect();
+    let file_image_formats: HashMap<String, ImageFormat> =
+        opts.file_image_formats.into_iter().collect();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let mut extension_targets = config.extension_targets;
+    extension_targets.extend(opts.extension_targets);
     let parameters = ConvertParameters {
         in_place: opts.in_place,
         archive: opts.archive,
         files: all_files.clone(),
-        default_password: opts.default_password.unwrap_or_default(),
+        passwords,
+        file_passwords,
+        max_password_attempts: opts.max_password_attempts,
         max_pages_converted_in_parallele: opts.max_tesseract_process,
         ocr: opts.ocr_lang,
+        audio_format: opts.audio_format,
+        image_format: opts.image_format,
+        file_image_formats,
+        video_audio: !opts.no_video_audio,
+        html_single_page: opts.html_single_page,
+        dpi: opts.resolution.dpi(),
+        pages: opts.pages.unwrap_or_default(),
+        pixel_format: opts.pixel_format,
+        page_compression: opts
+            .jpeg_quality
+            .map_or(PageCompression::Lossless, |quality| PageCompression::Jpeg {
+                quality,
+            }),
+        output_profile: opts.output_profile,
+        target: opts
+            .target
+            .or(config.target)
+            .unwrap_or_else(|| DISPOSABLE_TARGET.to_string()),
+        extension_targets,
+        parallel_servers: opts.parallel_servers,
+        isolation: opts.isolation,
         stderr: opts.no_fancy_ui,
     };
     let (transmitter_convert_events, receiver_convert_events) = mpsc::channel();
     thread::spawn(move || {
-        convert_all_files(&transmitter_convert_events, parameters).unwrap();
+        // The files it could not convert are reported as failed, with the reason.
+        if let Err(e) = convert_all_files(&transmitter_convert_events, parameters) {
+            debug!("Conversion stopped: {}", e);
+        }
     });
 
     if opts.no_fancy_ui {
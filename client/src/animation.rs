@@ -0,0 +1,110 @@
+use crate::{
+    image_size_is_valid, limit_exceeded, png_color, read_page_header, transport::FromServer,
+    ConvertError, ConvertEvent, Provenance,
+};
+use log::debug;
+use qubes_converter_common::protocol::{self, PixelFormat, ProtocolError, ServerMessage};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    sync::mpsc::Sender,
+};
+
+// Must match the server.
+const MAX_IMAGE_FRAMES: u16 = 1_000;
+
+// Header of one frame: its delay, then the dimensions of its page.
+fn read_frame_header(
+    process_stdout: &mut FromServer,
+    pixel_format: PixelFormat,
+) -> Result<(u16, u32, u32), ConvertError> {
+    let delay_milliseconds = match ServerMessage::read_from(process_stdout)? {
+        ServerMessage::AnimationFrame { delay_milliseconds } => delay_milliseconds,
+        ServerMessage::Failure(failure) => return Err(failure.into()),
+        _ => return Err(ProtocolError::InvalidValue("expected an animation frame").into()),
+    };
+    let (width, height) = read_page_header(process_stdout, pixel_format)?;
+    Ok((delay_milliseconds, width, height))
+}
+
+fn receive_frames(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    output_file: &str,
+    number_frames: u16,
+    pixel_format: PixelFormat,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    let mut encoder = None;
+    for frame in 0..number_frames {
+        let (delay_milliseconds, width, height) = read_frame_header(process_stdout, pixel_format)?;
+        if width == 0 || height == 0 || !image_size_is_valid(width, height, 4) {
+            return Err(limit_exceeded(
+                "Max image size exceeded: Probably DOS attempt",
+            ));
+        }
+        let data = protocol::read_pixels(process_stdout, width, height, pixel_format)?;
+        // Frames are full images, all of them must have the size of the first one.
+        let (writer, size) = match &mut encoder {
+            Some(encoder) => encoder,
+            None => {
+                let mut png_encoder =
+                    png::Encoder::new(BufWriter::new(File::create(output_file)?), width, height);
+                let (color, depth) = png_color(pixel_format);
+                png_encoder.set_color(color);
+                png_encoder.set_depth(depth);
+                png_encoder.set_animated(number_frames.into(), 0)?;
+                for (key, value) in provenance.entries() {
+                    png_encoder.add_itxt_chunk(key.to_string(), value)?;
+                }
+                encoder.insert((png_encoder.write_header()?, (width, height)))
+            }
+        };
+        if *size != (width, height) {
+            return Err(ProtocolError::InvalidValue("frames of different sizes").into());
+        }
+        writer.set_frame_delay(delay_milliseconds, 1_000)?;
+        writer.write_image_data(&data)?;
+        mpsc_sender.send(ConvertEvent::PageConverted {
+            file: source_file.to_string(),
+            page: frame,
+        })?;
+    }
+    if let Some((writer, _)) = encoder {
+        writer.finish()?;
+    }
+    Ok(())
+}
+
+/// Receive every frame of an animated image and rebuild `output_file` as an animated PNG from
+/// them only. Pages are frames.
+pub(crate) fn receive_animation(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    output_file: &str,
+    number_frames: u16,
+    pixel_format: PixelFormat,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    debug!("Animated image: {} frames", number_frames);
+    if number_frames > MAX_IMAGE_FRAMES {
+        return Err(limit_exceeded(
+            "Max frame number exceeded: Probably DOS attempt",
+        ));
+    }
+    let result = receive_frames(
+        mpsc_sender,
+        process_stdout,
+        source_file,
+        output_file,
+        number_frames,
+        pixel_format,
+        provenance,
+    );
+    if result.is_err() {
+        let _ = fs::remove_file(output_file);
+    }
+    result
+}
@@ -0,0 +1,220 @@
+use crate::{
+    receive_document,
+    transport::{FromServer, ToServer},
+    ConvertError, ConvertEvent, ConvertParameters, Provenance,
+};
+use log::debug;
+use qubes_converter_common::protocol::{ProtocolError, ServerMessage};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc::Sender, Mutex},
+};
+
+// Must match the server, an archive in an archive in an archive is the deepest accepted. Emails
+// count as archives of attachments.
+const MAX_ARCHIVE_DEPTH: u8 = 3;
+const MAX_PATH_COMPONENTS: usize = 32;
+const MAX_COMPONENT_LENGTH: usize = 200;
+
+/// Turn a member path chosen by the untrusted archive into a relative path that can not leave the
+/// output directory: no root, no "..", no control characters. `None` when nothing is left.
+fn sanitize_member_path(path: &str) -> Option<PathBuf> {
+    let mut sanitized = PathBuf::new();
+    for component in path.split(['/', '\\']) {
+        if component.is_empty() || component == "." || component == ".." {
+            continue;
+        }
+        let mut cleaned = String::new();
+        for c in component.chars() {
+            if cleaned.len() + c.len_utf8() > MAX_COMPONENT_LENGTH {
+                break;
+            }
+            cleaned.push(if c.is_control() { '_' } else { c });
+        }
+        sanitized.push(cleaned);
+    }
+    let number_components = sanitized.components().count();
+    if number_components == 0 || number_components > MAX_PATH_COMPONENTS {
+        return None;
+    }
+    Some(sanitized)
+}
+
+// Two members can end up with the same sanitized path, never let the second overwrite the first.
+fn unused_output_base(used: &mut HashSet<PathBuf>, output_base: PathBuf) -> PathBuf {
+    let mut candidate = output_base.clone();
+    let mut suffix = 1;
+    while used.contains(&candidate) {
+        let mut name = output_base.file_name().unwrap().to_os_string();
+        name.push(format!("_{}", suffix));
+        candidate = output_base.with_file_name(name);
+        suffix += 1;
+    }
+    used.insert(candidate.clone());
+    candidate
+}
+
+// Archives and emails are both containers.
+pub(crate) fn check_depth(depth: u8) -> Result<(), ProtocolError> {
+    if depth >= MAX_ARCHIVE_DEPTH {
+        return Err(ProtocolError::InvalidValue("archives nested too deep"));
+    }
+    Ok(())
+}
+
+/// Receive one document nested in a container. A document that can not be converted is
+/// reported and skipped.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn receive_nested_document(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdin: &Mutex<ToServer>,
+    process_stdout: &mut FromServer,
+    label: &str,
+    temporary_directory: &str,
+    parameters: &ConvertParameters,
+    provenance: &Provenance,
+    output_base: &str,
+    depth: u8,
+) -> Result<(), ConvertError> {
+    fs::create_dir_all(temporary_directory)?;
+    let result = receive_document(
+        mpsc_sender,
+        process_stdin,
+        process_stdout,
+        label,
+        temporary_directory,
+        parameters,
+        provenance,
+        output_base,
+        depth,
+    );
+    fs::remove_dir_all(temporary_directory)?;
+    match result {
+        Ok(()) => Ok(()),
+        // The server moves on to the next document after a failure, anything else means the
+        // stream can not be trusted anymore.
+        Err(e) if e.keeps_stream() => {
+            mpsc_sender.send(ConvertEvent::Failure {
+                file: label.to_string(),
+                message: e.to_string(),
+            })?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Receive `number_members` members and rebuild their directory tree under `output_directory`.
+/// Each member is reported as the page `first_page + member_id` of `label`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn receive_members(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdin: &Mutex<ToServer>,
+    process_stdout: &mut FromServer,
+    label: &str,
+    temporary_directory: &str,
+    parameters: &ConvertParameters,
+    provenance: &Provenance,
+    output_directory: &str,
+    first_page: u16,
+    number_members: u16,
+    depth: u8,
+) -> Result<(), ConvertError> {
+    fs::create_dir_all(output_directory)?;
+    let mut used_output_bases = HashSet::new();
+    for member_id in 0..number_members {
+        let path = match ServerMessage::read_from(process_stdout)? {
+            ServerMessage::ArchiveMember { path } => path,
+            ServerMessage::Failure(failure) => return Err(failure.into()),
+            _ => return Err(ProtocolError::InvalidValue("expected an archive member").into()),
+        };
+        let member_path = sanitize_member_path(&path)
+            .unwrap_or_else(|| PathBuf::from(format!("member_{}", member_id)));
+        debug!("Archive member {:?} stored as {:?}", path, member_path);
+        let output_base = unused_output_base(
+            &mut used_output_bases,
+            Path::new(output_directory).join(&member_path),
+        );
+        fs::create_dir_all(output_base.parent().unwrap())?;
+        receive_nested_document(
+            mpsc_sender,
+            process_stdin,
+            process_stdout,
+            &format!("{}/{}", label, member_path.display()),
+            &format!("{}/member_{}", temporary_directory, member_id),
+            parameters,
+            provenance,
+            output_base.to_str().unwrap(),
+            depth + 1,
+        )?;
+        mpsc_sender.send(ConvertEvent::PageConverted {
+            file: label.to_string(),
+            page: first_page + member_id,
+        })?;
+    }
+    Ok(())
+}
+
+/// Receive every member of an archive and rebuild its directory tree under `output_directory`.
+/// A member that can not be converted is reported and skipped, pages are members.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn receive_archive(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdin: &Mutex<ToServer>,
+    process_stdout: &mut FromServer,
+    label: &str,
+    temporary_directory: &str,
+    parameters: &ConvertParameters,
+    provenance: &Provenance,
+    output_directory: &str,
+    number_members: u16,
+    depth: u8,
+) -> Result<(), ConvertError> {
+    check_depth(depth)?;
+    receive_members(
+        mpsc_sender,
+        process_stdin,
+        process_stdout,
+        label,
+        temporary_directory,
+        parameters,
+        provenance,
+        output_directory,
+        0,
+        number_members,
+        depth,
+    )
+}
+
+#[test]
+fn sanitize_member_path_test() {
+    assert_eq!(
+        sanitize_member_path("docs/report.docx"),
+        Some(PathBuf::from("docs/report.docx"))
+    );
+    assert_eq!(
+        sanitize_member_path("/etc/../../passwd"),
+        Some(PathBuf::from("etc/passwd"))
+    );
+    assert_eq!(
+        sanitize_member_path("..\\..\\windows\\evil.exe"),
+        Some(PathBuf::from("windows/evil.exe"))
+    );
+    assert_eq!(
+        sanitize_member_path("bad\nname"),
+        Some(PathBuf::from("bad_name"))
+    );
+    assert_eq!(sanitize_member_path("../.."), None);
+    assert_eq!(sanitize_member_path(&"a/".repeat(100)), None);
+    let long_name = "a".repeat(1_000);
+    assert_eq!(
+        sanitize_member_path(&long_name)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .len(),
+        MAX_COMPONENT_LENGTH
+    );
+}
@@ -0,0 +1,173 @@
+use crate::{limit_exceeded, transport::FromServer, ConvertError, ConvertEvent};
+use log::debug;
+use qubes_converter_common::protocol::{self, ProtocolError, ServerMessage};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    process::Command,
+    str::FromStr,
+    sync::mpsc::Sender,
+};
+
+const MAX_AUDIO_SAMPLE_RATE: u32 = 192_000;
+const MAX_AUDIO_CHANNELS: u8 = 8;
+const MAX_AUDIO_DURATION_SECONDS: u64 = 4 * 3600;
+const MAX_AUDIO_CHUNK_FRAMES: u32 = 1_048_576;
+// Size of the RIFF header written before the samples.
+const WAV_HEADER_SIZE: u32 = 44;
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+}
+impl AudioFormat {
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+        }
+    }
+}
+impl FromStr for AudioFormat {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "wav" => Ok(Self::Wav),
+            "flac" => Ok(Self::Flac),
+            _ => Err(format!("Unknown audio format: {}", value)),
+        }
+    }
+}
+
+fn write_wav_header<W: Write>(
+    writer: &mut W,
+    sample_rate: u32,
+    channels: u8,
+    data_size: u32,
+) -> Result<(), std::io::Error> {
+    let block_align = u16::from(channels) * 2;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16_u32.to_le_bytes())?;
+    // PCM
+    writer.write_all(&1_u16.to_le_bytes())?;
+    writer.write_all(&u16::from(channels).to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    // Bits per sample
+    writer.write_all(&16_u16.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    Ok(())
+}
+
+/// Receive an `AudioFormat` followed by `number_chunks` chunks, and write them to a WAV file.
+/// `chunk_received` is called after each chunk.
+pub(crate) fn receive_wav(
+    process_stdout: &mut FromServer,
+    wav_file_path: &str,
+    number_chunks: u16,
+    mut chunk_received: impl FnMut(u16) -> Result<(), ConvertError>,
+) -> Result<(), ConvertError> {
+    let (sample_rate, channels) = match ServerMessage::read_from(process_stdout)? {
+        ServerMessage::AudioFormat {
+            sample_rate,
+            channels,
+        } => (sample_rate, channels),
+        ServerMessage::Failure(failure) => return Err(failure.into()),
+        _ => return Err(ProtocolError::InvalidValue("expected the audio format").into()),
+    };
+    debug!("Audio: {} Hz, {} channels", sample_rate, channels);
+    if sample_rate == 0 || sample_rate > MAX_AUDIO_SAMPLE_RATE {
+        return Err(limit_exceeded(
+            "Invalid audio sample rate: Probably DOS attempt",
+        ));
+    }
+    if channels == 0 || channels > MAX_AUDIO_CHANNELS {
+        return Err(limit_exceeded(
+            "Invalid number of audio channels: Probably DOS attempt",
+        ));
+    }
+    let max_frames = MAX_AUDIO_DURATION_SECONDS * u64::from(sample_rate);
+    let frame_size = u64::from(channels) * 2;
+
+    let mut wav_file = BufWriter::new(File::create(wav_file_path)?);
+    write_wav_header(&mut wav_file, sample_rate, channels, 0)?;
+    let mut total_frames: u64 = 0;
+    for chunk in 0..number_chunks {
+        let number_frames = match ServerMessage::read_from(process_stdout)? {
+            ServerMessage::AudioChunk { number_frames } => number_frames,
+            ServerMessage::Failure(failure) => return Err(failure.into()),
+            _ => return Err(ProtocolError::InvalidValue("expected an audio chunk").into()),
+        };
+        total_frames += u64::from(number_frames);
+        if number_frames > MAX_AUDIO_CHUNK_FRAMES || total_frames > max_frames {
+            return Err(limit_exceeded(
+                "Max audio duration exceeded: Probably DOS attempt",
+            ));
+        }
+        let samples = protocol::read_samples(process_stdout, number_frames, channels)?;
+        wav_file.write_all(&samples)?;
+        chunk_received(chunk)?;
+    }
+    let data_size = u32::try_from(total_frames * frame_size)
+        .ok()
+        .filter(|size| *size <= u32::MAX - WAV_HEADER_SIZE)
+        .ok_or_else(|| limit_exceeded("Audio too long to be stored in a WAV file"))?;
+    wav_file.seek(SeekFrom::Start(0))?;
+    write_wav_header(&mut wav_file, sample_rate, channels, data_size)?;
+    wav_file.flush()?;
+    Ok(())
+}
+
+/// Receive the samples of one audio file, and rebuild `output_file` from them only.
+pub(crate) fn receive_audio(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    temporary_file_base: &str,
+    output_file: &str,
+    number_chunks: u16,
+    audio_format: AudioFormat,
+) -> Result<(), ConvertError> {
+    let wav_file_path = format!("{}.wav", temporary_file_base);
+    receive_wav(process_stdout, &wav_file_path, number_chunks, |chunk| {
+        mpsc_sender.send(ConvertEvent::PageConverted {
+            file: source_file.to_string(),
+            page: chunk,
+        })?;
+        Ok(())
+    })?;
+    match audio_format {
+        AudioFormat::Wav => {
+            fs::copy(&wav_file_path, output_file)?;
+        }
+        AudioFormat::Flac => {
+            let command_output = Command::new("flac")
+                .args(["--silent", "--force", "-o", output_file, &wav_file_path])
+                .output()?;
+            if !command_output.status.success() {
+                return Err(ConvertError::tool_failed(
+                    "flac",
+                    String::from_utf8_lossy(&command_output.stderr),
+                ));
+            }
+        }
+    }
+    fs::remove_file(&wav_file_path)?;
+    Ok(())
+}
+
+#[test]
+fn wav_header_test() {
+    let mut header = Vec::new();
+    write_wav_header(&mut header, 44_100, 2, 1_000).unwrap();
+    assert_eq!(header.len(), WAV_HEADER_SIZE as usize);
+    assert_eq!(&header[..4], b"RIFF");
+    assert_eq!(header[4..8], (WAV_HEADER_SIZE - 8 + 1_000).to_le_bytes());
+    assert_eq!(header[28..32], (44_100_u32 * 4).to_le_bytes());
+    assert_eq!(header[40..44], 1_000_u32.to_le_bytes());
+}
@@ -0,0 +1,84 @@
+use crate::{check_target, home_directory, ConvertError};
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Defaults of the user, read from `$XDG_CONFIG_HOME/qubes-converter/config`. Each line is
+/// `key = value`, empty lines and lines starting with `#` are ignored:
+/// - `target = @dispvm:office-dvm`: qrexec target of the servers.
+/// - `target.pdf = @dispvm:pdf-dvm`: qrexec target of the files with this extension.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct Config {
+    pub target: Option<String>,
+    /// Keyed by lowercase extension, without the dot.
+    pub extension_targets: HashMap<String, String>,
+}
+impl Config {
+    pub fn path() -> Result<PathBuf, ConvertError> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(path) if Path::new(&path).is_absolute() => PathBuf::from(path),
+            _ => home_directory()?.join(".config"),
+        };
+        Ok(config_home.join("qubes-converter").join("config"))
+    }
+    /// Read the configuration file. Without one, there is nothing to change from the defaults.
+    pub fn load() -> Result<Self, ConvertError> {
+        let path = Self::path()?;
+        match fs::read_to_string(&path) {
+            Ok(content) => content
+                .parse()
+                .map_err(|e| ConvertError::InvalidConfig(format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+impl FromStr for Config {
+    type Err = String;
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| format!("line {}: expected key = value", number + 1))?;
+            check_target(value).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            match key.strip_prefix("target.") {
+                Some(extension) if !extension.is_empty() => {
+                    config
+                        .extension_targets
+                        .insert(extension.to_lowercase(), value.to_string());
+                }
+                _ if key == "target" => config.target = Some(value.to_string()),
+                _ => return Err(format!("line {}: unknown key {}", number + 1, key)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[test]
+fn config_test() {
+    let config: Config = "# Plain PDFs do not need LibreOffice\n\
+                          target = @dispvm:office-dvm\n\
+                          \n\
+                          target.PDF = @dispvm:pdf-dvm\n"
+        .parse()
+        .unwrap();
+    assert_eq!(config.target.as_deref(), Some("@dispvm:office-dvm"));
+    assert_eq!(
+        config.extension_targets.get("pdf").map(String::as_str),
+        Some("@dispvm:pdf-dvm")
+    );
+    assert_eq!("".parse(), Ok(Config::default()));
+    assert!("target".parse::<Config>().is_err());
+    assert!("dispvm = @dispvm".parse::<Config>().is_err());
+    assert!("target = --help".parse::<Config>().is_err());
+}
@@ -0,0 +1,515 @@
+use crate::{
+    font::EmbeddedFont,
+    image_size_is_valid, limit_exceeded,
+    pdf::{deflate, info_dictionary, ObjectId, PdfWriter},
+    read_page_header,
+    transport::FromServer,
+    write_png, ConvertError, ConvertEvent, ConvertParameters, OutputProfile, PageCompression,
+    Provenance,
+};
+use image::{codecs::jpeg::JpegEncoder, ExtendedColorType};
+use log::debug;
+use qubes_converter_common::protocol::{self, PixelFormat};
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    process::{Child, Command, Stdio},
+    sync::mpsc::Sender,
+};
+
+// Tesseract recognizing the words of one page, from a PNG copy of it.
+struct Ocr {
+    process: Child,
+    base: String,
+}
+
+// A page whose image is already written, its page object waits for the OCR.
+struct PendingPage {
+    number: u16,
+    id: ObjectId,
+    image: ObjectId,
+    width: u32,
+    height: u32,
+    ocr: Option<Ocr>,
+}
+
+// A word found by tesseract, in points from the bottom left corner of the page.
+struct Word {
+    text: String,
+    left: f64,
+    bottom: f64,
+    width: f64,
+    size: f64,
+}
+
+// Size in points of `pixels` rendered at `dpi`: the original paper size.
+fn points(pixels: u32, dpi: u16) -> f64 {
+    f64::from(pixels) * 72.0 / f64::from(dpi)
+}
+
+fn start_ocr(
+    base: String,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+    parameters: &ConvertParameters,
+    ocr_lang: &str,
+) -> Result<Ocr, ConvertError> {
+    let png_file_path = format!("{}.png", base);
+    write_png(
+        &png_file_path,
+        width,
+        height,
+        parameters.pixel_format,
+        pixels,
+        None,
+    )?;
+    let process = Command::new("tesseract")
+        .args([
+            png_file_path.as_str(),
+            &base,
+            "-l",
+            ocr_lang,
+            "--dpi",
+            &parameters.dpi.to_string(),
+            "tsv",
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| ConvertError::tool_failed("tesseract", e.to_string()))?;
+    Ok(Ocr { process, base })
+}
+
+// Words found by tesseract, from its TSV output.
+fn page_words(tsv: &str, page_height: f64, dpi: u16) -> Vec<Word> {
+    let scale = 72.0 / f64::from(dpi);
+    let mut words = Vec::new();
+    for line in tsv.lines().skip(1) {
+        let fields: Vec<&str> = line.splitn(12, '\t').collect();
+        // Level 5 rows are words, the others group them.
+        if fields.len() != 12 || fields[0] != "5" {
+            continue;
+        }
+        let number = |index: usize| fields[index].parse::<u32>().ok().map(f64::from);
+        let (left, top, width, height) = match (number(6), number(7), number(8), number(9)) {
+            (Some(left), Some(top), Some(width), Some(height)) => (left, top, width, height),
+            _ => continue,
+        };
+        let text = fields[11].trim();
+        if text.is_empty() || width == 0.0 || height == 0.0 {
+            continue;
+        }
+        words.push(Word {
+            text: text.to_string(),
+            left: left * scale,
+            bottom: page_height - (top + height) * scale,
+            width: width * scale,
+            size: height * scale,
+        });
+    }
+    words
+}
+
+// The words as invisible text over the page image, so they can be searched and copied. The glyphs
+// are stretched to cover each word, `skipped` characters are left out.
+fn text_layer(words: &[Word], font: &EmbeddedFont, skipped: &[char]) -> String {
+    let mut content = String::from("BT\n3 Tr\n");
+    for word in words {
+        let glyphs: Vec<(u16, i32)> = word
+            .text
+            .chars()
+            .filter(|c| !skipped.contains(c))
+            .filter_map(|c| font.encode(c))
+            .collect();
+        if glyphs.is_empty() {
+            continue;
+        }
+        let natural_width: i32 = glyphs.iter().map(|&(_, width)| width).sum();
+        let stretch = if natural_width > 0 {
+            100.0 * word.width * 1000.0 / (f64::from(natural_width) * word.size)
+        } else {
+            100.0
+        };
+        let cids: String = glyphs
+            .iter()
+            .map(|(cid, _)| format!("{:04x}", cid))
+            .collect();
+        content.push_str(&format!(
+            "/F1 {:.2} Tf\n{:.2} Tz\n1 0 0 1 {:.2} {:.2} Tm\n<{}> Tj\n",
+            word.size, stretch, word.left, word.bottom, cids
+        ));
+    }
+    content.push_str("ET\n");
+    content
+}
+
+fn finish_ocr(ocr: Ocr, page_height: f64, dpi: u16) -> Result<Vec<Word>, ConvertError> {
+    let Ocr { mut process, base } = ocr;
+    let status = process.wait()?;
+    let tsv_file_path = format!("{}.tsv", base);
+    let tsv = fs::read_to_string(&tsv_file_path);
+    let _ = fs::remove_file(format!("{}.png", base));
+    let _ = fs::remove_file(&tsv_file_path);
+    if !status.success() {
+        return Err(ConvertError::tool_failed(
+            "tesseract",
+            format!("exited with {}", status),
+        ));
+    }
+    Ok(page_words(&tsv?, page_height, dpi))
+}
+
+// Store the pixels of a page as an image in their own color depth. The transparency of RGBA pages
+// goes to a soft mask, only when they have some.
+fn add_page_image<W: Write>(
+    pdf: &mut PdfWriter<W>,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    parameters: &ConvertParameters,
+) -> Result<ObjectId, ConvertError> {
+    let (color, color_space, bits, alpha) = match parameters.pixel_format {
+        PixelFormat::Rgba => {
+            let mut color = Vec::with_capacity(pixels.len() / 4 * 3);
+            let mut alpha = Vec::with_capacity(pixels.len() / 4);
+            for pixel in pixels.chunks_exact(4) {
+                color.extend_from_slice(&pixel[..3]);
+                alpha.push(pixel[3]);
+            }
+            let alpha = Some(alpha).filter(|alpha| alpha.iter().any(|&value| value != u8::MAX));
+            (color, "/DeviceRGB", 8, alpha)
+        }
+        PixelFormat::Rgb => (pixels, "/DeviceRGB", 8, None),
+        PixelFormat::Gray => (pixels, "/DeviceGray", 8, None),
+        // Rows are padded to a byte and 1 is white, like PDF expects.
+        PixelFormat::Bilevel => (pixels, "/DeviceGray", 1, None),
+    };
+    let image_dictionary = |color_space: &str, bits: u8, filter: &str| {
+        format!(
+            "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent {} /Filter {}",
+            width, height, color_space, bits, filter
+        )
+    };
+    let mask = match alpha {
+        Some(alpha) => Some(pdf.add_stream(
+            &image_dictionary("/DeviceGray", 8, "/FlateDecode"),
+            &deflate(&alpha)?,
+        )?),
+        None => None,
+    };
+    let (filter, data) = match parameters.page_compression {
+        PageCompression::Jpeg { quality } if bits == 8 => {
+            let color_type = if color_space == "/DeviceRGB" {
+                ExtendedColorType::Rgb8
+            } else {
+                ExtendedColorType::L8
+            };
+            let mut data = Vec::new();
+            JpegEncoder::new_with_quality(&mut data, quality)
+                .encode(&color, width, height, color_type)?;
+            ("/DCTDecode", data)
+        }
+        _ => ("/FlateDecode", deflate(&color)?),
+    };
+    let mut dictionary = image_dictionary(color_space, bits, filter);
+    if let Some(mask) = mask {
+        dictionary.push_str(&format!(" /SMask {}", mask));
+    }
+    Ok(pdf.add_stream(&dictionary, &data)?)
+}
+
+// Draw the page image on the whole page, with the OCR text over it.
+fn write_page<W: Write>(
+    pdf: &mut PdfWriter<W>,
+    page: &PendingPage,
+    pages: ObjectId,
+    text: Option<(&str, ObjectId)>,
+    dpi: u16,
+) -> Result<(), ConvertError> {
+    let width = points(page.width, dpi);
+    let height = points(page.height, dpi);
+    let mut content = format!("q\n{:.2} 0 0 {:.2} 0 0 cm\n/Im0 Do\nQ\n", width, height);
+    let mut resources = format!("/XObject << /Im0 {} >>", page.image);
+    if let Some((text, font)) = text {
+        content.push_str(text);
+        resources.push_str(&format!(" /Font << /F1 {} >>", font));
+    }
+    let contents = pdf.add_stream("/Filter /FlateDecode", &deflate(content.as_bytes())?)?;
+    pdf.write_object(
+        page.id,
+        &format!(
+            "<< /Type /Page /Parent {} /MediaBox [0 0 {:.2} {:.2}] /Resources << {} >> /Contents {} >>",
+            pages, width, height, resources, contents
+        ),
+    )?;
+    Ok(())
+}
+
+// Pages are finished in order. Without OCR they are written right away, otherwise their words are
+// kept until the font for the text of all pages can be written.
+fn finish_oldest_page<W: Write>(
+    mpsc_sender: &Sender<ConvertEvent>,
+    source_file: &str,
+    pdf: &mut PdfWriter<W>,
+    pending_pages: &mut VecDeque<PendingPage>,
+    recognized_pages: &mut Vec<(PendingPage, Vec<Word>)>,
+    pages: ObjectId,
+    dpi: u16,
+) -> Result<(), ConvertError> {
+    if let Some(mut page) = pending_pages.pop_front() {
+        let number = page.number;
+        match page.ocr.take() {
+            Some(ocr) => {
+                let words = finish_ocr(ocr, points(page.height, dpi), dpi)?;
+                recognized_pages.push((page, words));
+            }
+            None => write_page(pdf, &page, pages, None, dpi)?,
+        }
+        mpsc_sender.send(ConvertEvent::PageConverted {
+            file: source_file.to_string(),
+            page: number,
+        })?;
+    }
+    Ok(())
+}
+
+// Embed one font for the words of all pages and write these pages. The text is invisible, missing
+// glyphs only matter to PDF/A: these characters are left out of the text.
+fn write_recognized_pages<W: Write>(
+    pdf: &mut PdfWriter<W>,
+    recognized_pages: &[(PendingPage, Vec<Word>)],
+    pages: ObjectId,
+    parameters: &ConvertParameters,
+) -> Result<(), ConvertError> {
+    let text: Vec<&str> = recognized_pages
+        .iter()
+        .flat_map(|(_, words)| words.iter().map(|word| word.text.as_str()))
+        .collect();
+    let font = EmbeddedFont::for_text(&text.join("\n"))?.ok_or_else(|| {
+        ConvertError::tool_failed(
+            "font",
+            "no TrueType font for the text layer is installed, like DejaVu Sans Mono".to_string(),
+        )
+    })?;
+    let skipped = match parameters.output_profile {
+        OutputProfile::Standard => Vec::new(),
+        OutputProfile::PdfA2b => font.missing_characters(),
+    };
+    let font_id = font.write(pdf)?;
+    for (page, words) in recognized_pages {
+        let text = text_layer(words, &font, &skipped);
+        write_page(pdf, page, pages, Some((&text, font_id)), parameters.dpi)?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_document(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    temporary_directory: &str,
+    output_file: &str,
+    number_pages: u16,
+    source_pages: &[u16],
+    parameters: &ConvertParameters,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    let mut pdf = PdfWriter::new(BufWriter::new(File::create(output_file)?))?;
+    let pages = pdf.reserve();
+    let mut kids = Vec::with_capacity(number_pages.into());
+    let mut pending_pages = VecDeque::new();
+    let mut recognized_pages = Vec::new();
+
+    // Tesseract process require gigantic amount of memory.
+    // Memory starving tesseract process will slow down everything and result in much MUCH worse
+    // performance (freezing, some kind of deadlock and crashing included).
+    // So the optimal amount of concurrent tesseract seems to be a computation between number of
+    // CPU physical core available and memory available.
+    //
+    // On my particular setup (highend gaming setup from late 2017, 8 physical core and 32 go ram)
+    // , "3" seems to be the best number for the fastest conversion.
+    // This number will vary depending on the hardware.
+    // In case of doubt, less tesseract process is better than more tesseract process.
+    let maximum_number_process = usize::from(parameters.max_pages_converted_in_parallele.max(1));
+
+    for number in 0..number_pages {
+        debug!("reading page size from server");
+        let (width, height) = read_page_header(process_stdout, parameters.pixel_format)?;
+        if width == 0 || height == 0 || !image_size_is_valid(width, height, 4) {
+            return Err(limit_exceeded(
+                "Max image size exceeded: Probably DOS attempt",
+            ));
+        }
+        debug!("reading page data from server");
+        let pixels = protocol::read_pixels(process_stdout, width, height, parameters.pixel_format)?;
+        let ocr = match &parameters.ocr {
+            Some(ocr_lang) => Some(start_ocr(
+                format!("{}/page_{}", temporary_directory, number),
+                width,
+                height,
+                &pixels,
+                parameters,
+                ocr_lang,
+            )?),
+            None => None,
+        };
+        // Only one page at a time is held in memory, its image is written right away.
+        let image = add_page_image(&mut pdf, width, height, pixels, parameters)?;
+        let id = pdf.reserve();
+        kids.push(id);
+        pending_pages.push_back(PendingPage {
+            number,
+            id,
+            image,
+            width,
+            height,
+            ocr,
+        });
+        // Without OCR every page is finished right away, otherwise once enough tesseract run.
+        if parameters.ocr.is_none() || pending_pages.len() >= maximum_number_process {
+            finish_oldest_page(
+                mpsc_sender,
+                source_file,
+                &mut pdf,
+                &mut pending_pages,
+                &mut recognized_pages,
+                pages,
+                parameters.dpi,
+            )?;
+        }
+    }
+    while !pending_pages.is_empty() {
+        finish_oldest_page(
+            mpsc_sender,
+            source_file,
+            &mut pdf,
+            &mut pending_pages,
+            &mut recognized_pages,
+            pages,
+            parameters.dpi,
+        )?;
+    }
+    if !recognized_pages.is_empty() {
+        write_recognized_pages(&mut pdf, &recognized_pages, pages, parameters)?;
+    }
+
+    let kids_references: Vec<String> = kids.iter().map(ToString::to_string).collect();
+    pdf.write_object(
+        pages,
+        &format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids_references.join(" "),
+            kids.len()
+        ),
+    )?;
+    let catalog_entries = match parameters.output_profile {
+        OutputProfile::Standard => String::new(),
+        OutputProfile::PdfA2b => format!(" {}", pdf.add_pdfa_entries()?),
+    };
+    let catalog = pdf.add_object(&format!(
+        "<< /Type /Catalog /Pages {}{} >>",
+        pages, catalog_entries
+    ))?;
+    // A partial conversion records the original numbers of its pages, like "1-10,45".
+    let mut info = provenance.entries();
+    if !parameters.pages.is_empty() {
+        info.push(("SourcePages", protocol::format_pages(source_pages)));
+    }
+    let info = pdf.add_object(&info_dictionary(&info))?;
+    pdf.finish(catalog, Some(info))?
+        .into_inner()
+        .map_err(io::Error::from)?
+        .sync_all()?;
+    Ok(())
+}
+
+/// Receive the pages of a paginated document and assemble them into `output_file` on the client,
+/// one page at a time. `source_pages` are the numbers of these pages in the original document.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn receive_document(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    temporary_directory: &str,
+    output_file: &str,
+    number_pages: u16,
+    source_pages: &[u16],
+    parameters: &ConvertParameters,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    let result = write_document(
+        mpsc_sender,
+        process_stdout,
+        source_file,
+        temporary_directory,
+        output_file,
+        number_pages,
+        source_pages,
+        parameters,
+        provenance,
+    );
+    if result.is_err() {
+        let _ = fs::remove_file(output_file);
+    }
+    result
+}
+
+#[cfg(test)]
+const TSV_HEADER: &str =
+    "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n";
+
+#[test]
+fn text_layer_test() {
+    let tsv = format!(
+        "{}1\t1\t0\t0\t0\t0\t0\t0\t1240\t1754\t-1\t\n\
+         5\t1\t1\t1\t1\t1\t150\t300\t60\t50\t96.5\tWord\n\
+         5\t1\t1\t1\t1\t2\t250\t300\t60\t50\t95.1\t \n",
+        TSV_HEADER
+    );
+    let words = page_words(&tsv, 842.0, 150);
+    assert_eq!(words.len(), 1);
+    let font = EmbeddedFont::for_text("Word")
+        .unwrap()
+        .expect("the tests need DejaVu Sans Mono");
+    let content = text_layer(&words, &font, &[]);
+    assert_eq!(content.matches(" Tj").count(), 1);
+    // 50 pixels at 150 dpi are 24 points, 4 glyphs of 0.602 em of this size are 57.792 points
+    // wide. CIDs follow the sorted characters: W, d, o, r.
+    assert!(content
+        .contains("/F1 24.00 Tf\n49.83 Tz\n1 0 0 1 72.00 674.00 Tm\n<0001000300040002> Tj\n"));
+    assert_eq!(text_layer(&[], &font, &[]), "BT\n3 Tr\nET\n");
+}
+
+#[test]
+fn text_layer_non_latin_test() {
+    let tsv = format!(
+        "{}5\t1\t1\t1\t1\t1\t150\t300\t60\t50\t91.0\tМир\n\
+         5\t1\t1\t1\t1\t2\t250\t300\t60\t50\t90.2\tκόσμος\n",
+        TSV_HEADER
+    );
+    let words = page_words(&tsv, 842.0, 150);
+    let font = EmbeddedFont::for_text("Мир\nκόσμος").unwrap().unwrap();
+    let content = text_layer(&words, &font, &[]);
+    let cids = |word: &str| -> String {
+        word.chars()
+            .map(|c| format!("{:04x}", font.encode(c).unwrap().0))
+            .collect()
+    };
+    assert!(content.contains(&format!("<{}> Tj", cids("Мир"))));
+    assert!(content.contains(&format!("<{}> Tj", cids("κόσμος"))));
+    assert!(!content.contains('?'));
+    // The ToUnicode map gives the recognized text back.
+    let mut pdf = PdfWriter::new(Vec::new()).unwrap();
+    let font_id = font.write(&mut pdf).unwrap();
+    let streams = crate::pdf::inflate_streams(&pdf.finish(font_id, None).unwrap());
+    let cmap = streams
+        .iter()
+        .map(|stream| String::from_utf8_lossy(stream))
+        .find(|stream| stream.contains("beginbfchar"))
+        .unwrap();
+    assert!(cmap.contains(&format!("<{}> <041c>", cids("М"))));
+    assert!(cmap.contains(&format!("<{}> <03ba>", cids("κ"))));
+}
@@ -0,0 +1,117 @@
+use crate::{
+    archive,
+    transport::{FromServer, ToServer},
+    ConvertError, ConvertEvent, ConvertParameters, Provenance,
+};
+use qubes_converter_common::protocol::ProtocolError;
+use std::sync::{mpsc::Sender, Mutex};
+
+/// Receive the rendered message as `<output_base>.pdf`, then its attachments in the
+/// `<output_base>.attachments` directory. The message is the first page, each attachment the next
+/// ones.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn receive_email(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdin: &Mutex<ToServer>,
+    process_stdout: &mut FromServer,
+    label: &str,
+    temporary_directory: &str,
+    parameters: &ConvertParameters,
+    provenance: &Provenance,
+    output_base: &str,
+    number_pages: u16,
+    depth: u8,
+) -> Result<(), ConvertError> {
+    archive::check_depth(depth)?;
+    let number_attachments = number_pages
+        .checked_sub(1)
+        .ok_or(ProtocolError::InvalidValue("email without message"))?;
+    archive::receive_nested_document(
+        mpsc_sender,
+        process_stdin,
+        process_stdout,
+        &format!("{}/message", label),
+        &format!("{}/message", temporary_directory),
+        parameters,
+        provenance,
+        output_base,
+        depth + 1,
+    )?;
+    mpsc_sender.send(ConvertEvent::PageConverted {
+        file: label.to_string(),
+        page: 0,
+    })?;
+    if number_attachments > 0 {
+        archive::receive_members(
+            mpsc_sender,
+            process_stdin,
+            process_stdout,
+            label,
+            temporary_directory,
+            parameters,
+            provenance,
+            &format!("{}.attachments", output_base),
+            1,
+            number_attachments,
+            depth,
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn receive_email_test() {
+    use crate::pdf::inflate_streams;
+    use qubes_converter_common::{protocol::ServerMessage, OutputType};
+    use std::{fs, io, sync::mpsc::channel};
+
+    // What the server renders of a message with encoded headers and a UTF-8 body.
+    let text = "From: Алиса <alice@example.org>\n\
+                Subject: Ελληνικά, русский, češtině\n\
+                \n\
+                Добрый день\n";
+    let mut stream = Vec::new();
+    ServerMessage::FileInfo {
+        number_pages: 1,
+        output_type: OutputType::Text,
+    }
+    .write_to(&mut stream)
+    .unwrap();
+    ServerMessage::TextChunk {
+        length: u32::try_from(text.len()).unwrap(),
+    }
+    .write_to(&mut stream)
+    .unwrap();
+    stream.extend_from_slice(text.as_bytes());
+    let mut process_stdout: FromServer = Box::new(io::Cursor::new(stream));
+    let process_stdin: Mutex<ToServer> = Mutex::new(Box::new(io::sink()));
+    let temporary_directory = crate::test_directory();
+    let output_base = format!("{}/email.trusted", temporary_directory);
+    let provenance = crate::test_provenance();
+    let (sender, receiver) = channel();
+    receive_email(
+        &sender,
+        &process_stdin,
+        &mut process_stdout,
+        "email.eml",
+        &format!("{}/work", temporary_directory),
+        &crate::test_parameters(),
+        &provenance,
+        &output_base,
+        1,
+        0,
+    )
+    .unwrap();
+    drop(sender);
+    assert!(!receiver
+        .iter()
+        .any(|event| matches!(event, ConvertEvent::Failure { .. })));
+    let pdf = fs::read(format!("{}.pdf", output_base)).unwrap();
+    fs::remove_dir_all(&temporary_directory).unwrap();
+    // Every character can be found back through the ToUnicode map of the embedded font.
+    let streams = String::from_utf8_lossy(&inflate_streams(&pdf).concat()).into_owned();
+    for c in text.chars().filter(|c| !c.is_ascii()) {
+        let code = format!("> <{:04x}>", u32::from(c));
+        assert!(streams.contains(&code), "{} is not searchable", c);
+    }
+}
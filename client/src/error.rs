@@ -0,0 +1,132 @@
+use qubes_converter_common::protocol::{ConversionFailure, ProtocolError};
+use std::{fmt, io, sync::mpsc::SendError};
+
+/// Why a file, or the whole batch, could not be converted.
+#[derive(Debug)]
+pub enum ConvertError {
+    /// Something is bigger than the client accepts. Sent by the server, it is probably a DOS
+    /// attempt.
+    LimitExceeded(String),
+    /// The server sent something the protocol does not allow.
+    Protocol(ProtocolError),
+    /// The server could not convert the file and moved on to the next one.
+    Conversion(ConversionFailure),
+    Io(io::Error),
+    /// A program run by the client, like tesseract or ffmpeg, failed.
+    ToolFailed {
+        tool: &'static str,
+        message: String,
+    },
+    /// The server could not be started, or stopped talking.
+    ServerDied(String),
+    /// Nobody listens to the `ConvertEvent`s anymore.
+    EventsClosed,
+    /// The qube asked for can not be a qrexec target, see `check_target`.
+    InvalidTarget(String),
+    /// The configuration file can not be understood.
+    InvalidConfig(String),
+}
+impl ConvertError {
+    /// Whether the client still knows where the server is in the batch after this error. Only a
+    /// failure reported by the server itself ends the file on both sides, after anything else the
+    /// rest of the file is still in the stream.
+    pub(crate) const fn keeps_stream(&self) -> bool {
+        matches!(self, Self::Conversion(_))
+    }
+    pub(crate) fn tool_failed(tool: &'static str, message: impl Into<String>) -> Self {
+        Self::ToolFailed {
+            tool,
+            message: message.into(),
+        }
+    }
+}
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LimitExceeded(message) => write!(f, "{}", message),
+            Self::Protocol(e) => write!(f, "{}", e),
+            Self::Conversion(failure) => write!(f, "{}", failure),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::ToolFailed { tool, message } => write!(f, "{} failed: {}", tool, message),
+            Self::ServerDied(message) => write!(f, "The convert server died: {}", message),
+            Self::EventsClosed => write!(f, "Nobody receives the conversion events anymore"),
+            Self::InvalidTarget(message) | Self::InvalidConfig(message) => write!(f, "{}", message),
+        }
+    }
+}
+impl std::error::Error for ConvertError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Protocol(e) => Some(e),
+            Self::Conversion(failure) => Some(failure),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for ConvertError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+// The protocol only reads and writes the pipes of the server, failing to do so means it is gone.
+impl From<ProtocolError> for ConvertError {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Io(e) => Self::ServerDied(e.to_string()),
+            e => Self::Protocol(e),
+        }
+    }
+}
+impl From<ConversionFailure> for ConvertError {
+    fn from(failure: ConversionFailure) -> Self {
+        Self::Conversion(failure)
+    }
+}
+impl<T> From<SendError<T>> for ConvertError {
+    fn from(_: SendError<T>) -> Self {
+        Self::EventsClosed
+    }
+}
+impl From<image::ImageError> for ConvertError {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            image::ImageError::IoError(e) => Self::Io(e),
+            e => Self::tool_failed("image encoder", e.to_string()),
+        }
+    }
+}
+impl From<png::DecodingError> for ConvertError {
+    fn from(e: png::DecodingError) -> Self {
+        match e {
+            png::DecodingError::IoError(e) => Self::Io(e),
+            e => Self::Io(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        }
+    }
+}
+impl From<png::EncodingError> for ConvertError {
+    fn from(e: png::EncodingError) -> Self {
+        match e {
+            png::EncodingError::IoError(e) => Self::Io(e),
+            e => Self::tool_failed("png encoder", e.to_string()),
+        }
+    }
+}
+
+#[test]
+fn convert_error_test() {
+    let failure = ConversionFailure::new(
+        qubes_converter_common::protocol::FailureReason::WrongPassword,
+        "report.pdf",
+    );
+    assert!(ConvertError::from(failure).keeps_stream());
+    let closed_pipe = ProtocolError::Io(io::Error::from(io::ErrorKind::UnexpectedEof));
+    assert!(matches!(
+        ConvertError::from(closed_pipe),
+        ConvertError::ServerDied(_)
+    ));
+    let violation = ConvertError::from(ProtocolError::InvalidValue("expected a page"));
+    assert!(matches!(violation, ConvertError::Protocol(_)));
+    assert!(!violation.keeps_stream());
+    assert!(!ConvertError::LimitExceeded("Max page number exceeded".to_string()).keeps_stream());
+}
@@ -0,0 +1,252 @@
+use crate::{
+    limit_exceeded,
+    pdf::{deflate, ObjectId, PdfWriter},
+    ConvertError,
+};
+use std::{collections::BTreeSet, fs, io::Write, iter};
+use ttf_parser::{name_id, Face, GlyphId};
+
+// TrueType fonts covering most scripts, where the usual distributions install them. Monospace
+// fonts come first, they keep the columns of the text aligned.
+const FONT_FILES: [&str; 11] = [
+    "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf",
+    "/usr/share/fonts/dejavu-sans-mono-fonts/DejaVuSansMono.ttf",
+    "/usr/share/fonts/TTF/DejaVuSansMono.ttf",
+    "/usr/share/fonts/truetype/noto/NotoSansMono-Regular.ttf",
+    "/usr/share/fonts/google-noto/NotoSansMono-Regular.ttf",
+    "/usr/share/fonts/noto/NotoSansMono-Regular.ttf",
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu-sans-fonts/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/truetype/droid/DroidSansFallbackFull.ttf",
+    "/usr/share/fonts/google-droid-sans-fonts/DroidSansFallbackFull.ttf",
+];
+
+// Glyph space of PDF fonts.
+const PDF_UNITS_PER_EM: i32 = 1000;
+
+// Embedded fonts are subsets, their name starts with a tag of 6 uppercase letters.
+const SUBSET_TAG: &str = "QUBESC";
+
+// The ToUnicode map may not have more entries per block.
+const MAX_CMAP_BLOCK: usize = 100;
+
+/// TrueType font to embed in a PDF, for the characters of one text. Each character gets its own
+/// CID, its index + 1, so that the ToUnicode map gives the text back even where the font has no
+/// glyph for it.
+pub(crate) struct EmbeddedFont {
+    data: Vec<u8>,
+    /// Sorted.
+    characters: Vec<char>,
+    glyphs: Vec<u16>,
+    /// In thousandths of the font size.
+    widths: Vec<i32>,
+}
+impl EmbeddedFont {
+    /// The installed font with glyphs for the most characters of `text`, `None` when there is no
+    /// usable font.
+    pub(crate) fn for_text(text: &str) -> Result<Option<Self>, ConvertError> {
+        let characters: Vec<char> = text
+            .chars()
+            .filter(|&c| c != '\n')
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if characters.len() >= usize::from(u16::MAX) {
+            return Err(limit_exceeded(
+                "Max number of different characters exceeded: Probably DOS attempt",
+            ));
+        }
+        let mut best: Option<(usize, Self)> = None;
+        for path in FONT_FILES {
+            let font = match fs::read(path)
+                .ok()
+                .and_then(|data| Self::new(data, &characters))
+            {
+                Some(font) => font,
+                None => continue,
+            };
+            let missing = font.missing_characters().len();
+            match &best {
+                Some((best_missing, _)) if *best_missing <= missing => {}
+                _ => best = Some((missing, font)),
+            }
+            if missing == 0 {
+                break;
+            }
+        }
+        Ok(best.map(|(_, font)| font))
+    }
+
+    // `None` when `data` is not a TrueType font with outlines.
+    fn new(data: Vec<u8>, characters: &[char]) -> Option<Self> {
+        let (glyphs, widths) = {
+            let face = Face::parse(&data, 0).ok()?;
+            face.tables().glyf?;
+            let units_per_em = i32::from(face.units_per_em());
+            characters
+                .iter()
+                .map(|&c| {
+                    let glyph = face.glyph_index(c).unwrap_or(GlyphId(0));
+                    let width = face.glyph_hor_advance(glyph).map_or(0, |advance| {
+                        i32::from(advance) * PDF_UNITS_PER_EM / units_per_em
+                    });
+                    (glyph.0, width)
+                })
+                .unzip()
+        };
+        Some(Self {
+            data,
+            characters: characters.to_vec(),
+            glyphs,
+            widths,
+        })
+    }
+
+    /// Characters shown as the missing glyph of the font.
+    pub(crate) fn missing_characters(&self) -> Vec<char> {
+        self.characters
+            .iter()
+            .zip(&self.glyphs)
+            .filter(|(_, &glyph)| glyph == 0)
+            .map(|(&c, _)| c)
+            .collect()
+    }
+
+    /// CID and width of `c`, which must be a character of the text.
+    pub(crate) fn encode(&self, c: char) -> Option<(u16, i32)> {
+        let index = self.characters.binary_search(&c).ok()?;
+        let cid = u16::try_from(index + 1).ok()?;
+        Some((cid, self.widths[index]))
+    }
+
+    /// Write the subset of the font with its metrics and maps, returns the Type0 font to use with
+    /// 2-byte CIDs.
+    pub(crate) fn write<W: Write>(&self, pdf: &mut PdfWriter<W>) -> Result<ObjectId, ConvertError> {
+        let face = Face::parse(&self.data, 0)
+            .map_err(|e| ConvertError::tool_failed("font", e.to_string()))?;
+        let name = format!("{}+{}", SUBSET_TAG, postscript_name(&face));
+        let mut used_glyphs = self.glyphs.clone();
+        used_glyphs.push(0);
+        used_glyphs.sort_unstable();
+        used_glyphs.dedup();
+        let subset = subsetter::subset(&self.data, 0, subsetter::Profile::pdf(&used_glyphs))
+            .map_err(|e| ConvertError::tool_failed("font", e.to_string()))?;
+        let font_file = pdf.add_stream(
+            &format!("/Length1 {} /Filter /FlateDecode", subset.len()),
+            &deflate(&subset)?,
+        )?;
+        let units_per_em = i32::from(face.units_per_em());
+        let scale = |value: i16| i32::from(value) * PDF_UNITS_PER_EM / units_per_em;
+        let bounding_box = face.global_bounding_box();
+        // Symbolic, and fixed pitch for monospace fonts.
+        let flags = if face.is_monospaced() { 4 | 1 } else { 4 };
+        let descriptor = pdf.add_object(&format!(
+            "<< /Type /FontDescriptor /FontName /{} /Flags {} /FontBBox [{} {} {} {}] /ItalicAngle {} /Ascent {} /Descent {} /CapHeight {} /StemV 80 /FontFile2 {} >>",
+            name,
+            flags,
+            scale(bounding_box.x_min),
+            scale(bounding_box.y_min),
+            scale(bounding_box.x_max),
+            scale(bounding_box.y_max),
+            face.italic_angle().round(),
+            scale(face.ascender()),
+            scale(face.descender()),
+            scale(face.capital_height().unwrap_or_else(|| face.ascender())),
+            font_file
+        ))?;
+        let cid_to_gid: Vec<u8> = iter::once(0)
+            .chain(self.glyphs.iter().copied())
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        let cid_to_gid_map = pdf.add_stream("/Filter /FlateDecode", &deflate(&cid_to_gid)?)?;
+        let widths: Vec<String> = self.widths.iter().map(ToString::to_string).collect();
+        let cid_font = pdf.add_object(&format!(
+            "<< /Type /Font /Subtype /CIDFontType2 /BaseFont /{} /CIDSystemInfo << /Registry (Adobe) /Ordering (Identity) /Supplement 0 >> /FontDescriptor {} /W [1 [{}]] /CIDToGIDMap {} >>",
+            name,
+            descriptor,
+            widths.join(" "),
+            cid_to_gid_map
+        ))?;
+        let to_unicode = pdf.add_stream(
+            "/Filter /FlateDecode",
+            &deflate(self.to_unicode().as_bytes())?,
+        )?;
+        Ok(pdf.add_object(&format!(
+            "<< /Type /Font /Subtype /Type0 /BaseFont /{} /Encoding /Identity-H /DescendantFonts [{}] /ToUnicode {} >>",
+            name, cid_font, to_unicode
+        ))?)
+    }
+
+    // CMap from the CIDs to the UTF-16BE of their characters, to copy and search the text.
+    fn to_unicode(&self) -> String {
+        let mut cmap = String::from(
+            "/CIDInit /ProcSet findresource begin\n\
+             12 dict begin\n\
+             begincmap\n\
+             /CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n\
+             /CMapName /Adobe-Identity-UCS def\n\
+             /CMapType 2 def\n\
+             1 begincodespacerange\n\
+             <0000> <FFFF>\n\
+             endcodespacerange\n",
+        );
+        for (block_index, block) in self.characters.chunks(MAX_CMAP_BLOCK).enumerate() {
+            cmap.push_str(&format!("{} beginbfchar\n", block.len()));
+            for (index, c) in block.iter().enumerate() {
+                let utf16: String = c
+                    .encode_utf16(&mut [0; 2])
+                    .iter()
+                    .map(|unit| format!("{:04x}", unit))
+                    .collect();
+                cmap.push_str(&format!(
+                    "<{:04x}> <{}>\n",
+                    block_index * MAX_CMAP_BLOCK + index + 1,
+                    utf16
+                ));
+            }
+            cmap.push_str("endbfchar\n");
+        }
+        cmap.push_str(
+            "endcmap\n\
+             CMapName currentdict /CMap defineresource pop\n\
+             end\n\
+             end\n",
+        );
+        cmap
+    }
+}
+
+// PostScript name of the font, without the characters PDF names can not hold.
+fn postscript_name(face: &Face) -> String {
+    let name: String = face
+        .names()
+        .into_iter()
+        .filter(|name| name.name_id == name_id::POST_SCRIPT_NAME)
+        .find_map(|name| name.to_string())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_graphic() && !"()<>[]{}/%#".contains(*c))
+        .collect();
+    if name.is_empty() {
+        "Font".to_string()
+    } else {
+        name
+    }
+}
+
+#[test]
+fn embedded_font_test() {
+    let font = EmbeddedFont::for_text("Жκ\nκ")
+        .unwrap()
+        .expect("the tests need DejaVu Sans Mono");
+    assert!(font.missing_characters().is_empty());
+    // Characters are sorted: U+03BA before U+0416.
+    assert_eq!(font.encode('κ').map(|(cid, _)| cid), Some(1));
+    assert_eq!(font.encode('Ж').map(|(cid, _)| cid), Some(2));
+    assert_eq!(font.encode('x'), None);
+    let cmap = font.to_unicode();
+    assert!(cmap.contains("2 beginbfchar\n<0001> <03ba>\n<0002> <0416>\nendbfchar"));
+    let emoji = EmbeddedFont::for_text("\u{1f600}").unwrap().unwrap();
+    assert!(emoji.to_unicode().contains("<0001> <d83dde00>"));
+}
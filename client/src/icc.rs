@@ -0,0 +1,127 @@
+// ICC profile of sRGB, the output intent of PDF/A documents. It is built here instead of being
+// shipped as a binary blob, so what ends in trusted files can be reviewed.
+
+// sRGB primaries adapted to D50, the white point of the XYZ connection space.
+const WHITE_POINT: [f64; 3] = [0.9642, 1.0, 0.8249];
+const RED: [f64; 3] = [0.4361, 0.2225, 0.0139];
+const GREEN: [f64; 3] = [0.3851, 0.7169, 0.0971];
+const BLUE: [f64; 3] = [0.1431, 0.0606, 0.7141];
+const CURVE_ENTRIES: u16 = 256;
+const HEADER_SIZE: usize = 128;
+
+#[allow(clippy::cast_possible_truncation)]
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for value in xyz {
+        tag.extend_from_slice(&s15_fixed16(value));
+    }
+    tag
+}
+
+// Version 2 description: ASCII only, empty Unicode and ScriptCode descriptions.
+fn text_description_tag(text: &str) -> Vec<u8> {
+    let mut tag = b"desc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    tag.extend_from_slice(&[0; 4 + 4 + 2 + 1 + 67]);
+    tag
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+    let mut tag = b"text\0\0\0\0".to_vec();
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    tag
+}
+
+// The sRGB transfer function, sampled.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn curve_tag() -> Vec<u8> {
+    let mut tag = b"curv\0\0\0\0".to_vec();
+    tag.extend_from_slice(&u32::from(CURVE_ENTRIES).to_be_bytes());
+    for entry in 0..CURVE_ENTRIES {
+        let encoded = f64::from(entry) / f64::from(CURVE_ENTRIES - 1);
+        let linear = if encoded <= 0.040_45 {
+            encoded / 12.92
+        } else {
+            ((encoded + 0.055) / 1.055).powf(2.4)
+        };
+        tag.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+    tag
+}
+
+/// Version 2.1 display profile of sRGB.
+pub(crate) fn srgb_profile() -> Vec<u8> {
+    let tags = [
+        (b"desc", text_description_tag("sRGB")),
+        (b"cprt", text_tag("No copyright, use freely")),
+        (b"wtpt", xyz_tag(WHITE_POINT)),
+        (b"rXYZ", xyz_tag(RED)),
+        (b"gXYZ", xyz_tag(GREEN)),
+        (b"bXYZ", xyz_tag(BLUE)),
+        (b"rTRC", curve_tag()),
+        (b"gTRC", curve_tag()),
+        (b"bTRC", curve_tag()),
+    ];
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let data_offset = HEADER_SIZE + 4 + 12 * tags.len();
+    for (signature, tag) in tags {
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&((data_offset + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(&tag);
+        // Tags start on 4 bytes boundaries.
+        data.resize(data.len() + (4 - data.len() % 4) % 4, 0);
+    }
+
+    let size = data_offset + data.len();
+    let mut profile = Vec::with_capacity(size);
+    profile.extend_from_slice(&(size as u32).to_be_bytes());
+    profile.extend_from_slice(&[0; 4]);
+    profile.extend_from_slice(&0x0210_0000_u32.to_be_bytes());
+    profile.extend_from_slice(b"mntrRGB XYZ ");
+    for date_part in [2022_u16, 1, 1, 0, 0, 0] {
+        profile.extend_from_slice(&date_part.to_be_bytes());
+    }
+    profile.extend_from_slice(b"acsp");
+    // Platform, flags, manufacturer, model, attributes and perceptual rendering intent.
+    profile.extend_from_slice(&[0; 4 + 4 + 4 + 4 + 8 + 4]);
+    for value in WHITE_POINT {
+        profile.extend_from_slice(&s15_fixed16(value));
+    }
+    // Creator, then the profile ID and reserved bytes of later versions.
+    profile.extend_from_slice(&[0; 4 + 16 + 28]);
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+    profile
+}
+
+#[test]
+fn srgb_profile_test() {
+    let profile = srgb_profile();
+    let read_u32 = |offset: usize| {
+        u32::from_be_bytes(profile[offset..offset + 4].try_into().unwrap()) as usize
+    };
+    assert_eq!(read_u32(0), profile.len());
+    assert_eq!(&profile[36..40], b"acsp");
+    assert_eq!(&profile[68..80], &xyz_tag(WHITE_POINT)[8..]);
+    let tag_count = read_u32(HEADER_SIZE);
+    assert_eq!(tag_count, 9);
+    for tag in 0..tag_count {
+        let entry = HEADER_SIZE + 4 + 12 * tag;
+        let (offset, size) = (read_u32(entry + 4), read_u32(entry + 8));
+        assert_eq!(offset % 4, 0);
+        assert!(offset + size <= profile.len());
+    }
+    // The transfer function goes from black to white.
+    let curve = curve_tag();
+    assert_eq!(&curve[12..14], &[0, 0]);
+    assert_eq!(&curve[curve.len() - 2..], &[0xff, 0xff]);
+}
@@ -1,41 +1,304 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::mem_forget)]
 use log::debug;
-use qubes_converter_common::OutputType;
+use qubes_converter_common::{
+    protocol::{self, ClientMessage, ConvertOptions, ProtocolError, ServerMessage},
+    OutputType,
+};
 use std::{
     collections::HashMap,
     convert::{TryFrom, TryInto},
-    ffi::OsString,
     fs::{self, File},
-    io::{Read, Write},
-    process::{Child, ChildStdout, Command, Stdio},
-    sync::mpsc::{channel, Sender},
-    thread, time,
+    io::{self, BufWriter},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
 };
 use uuid::Uuid;
 
 #[cfg(test)]
 use glob::glob;
 
+mod animation;
+mod archive;
+mod audio;
+mod config;
+mod document;
+mod email;
+mod error;
+mod font;
+mod icc;
+mod pdf;
+mod provenance;
+mod still_image;
+mod text;
+mod transport;
+mod video;
+pub use audio::AudioFormat;
+pub use config::Config;
+pub use error::ConvertError;
+pub use provenance::{read_provenance, Provenance};
+pub use qubes_converter_common::protocol::{parse_page_ranges, PageRange, PixelFormat};
+pub use still_image::ImageFormat;
+pub use transport::{
+    check_target, Connection, FromServer, LocalTransport, MemoryTransport, QrexecTransport, Server,
+    ServerFunction, ToServer, Transport, DISPOSABLE_TARGET,
+};
+
 const MAX_PAGES: u16 = 10_000;
-const MAX_IMG_WIDTH: usize = 10_000;
-const MAX_IMG_HEIGHT: usize = 10_000;
+const MAX_IMG_WIDTH: usize = protocol::MAX_PAGE_WIDTH as usize;
+const MAX_IMG_HEIGHT: usize = protocol::MAX_PAGE_HEIGHT as usize;
 const MAX_IMG_SIZE: usize = MAX_IMG_WIDTH * MAX_IMG_HEIGHT * 4;
 
-#[cfg(not(test))]
-const QREXEC_BINARY: &str = "/usr/bin/qrexec-client-vm";
+// Sample files of the integration tests, at the root of the repository.
+#[cfg(test)]
+const TEST_FILES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../tests/files");
+
+// The server built with the same profile as the tests, run without any qube. `None`, and the
+// integration test is skipped, when it has not been built.
+#[cfg(test)]
+fn test_transport() -> Option<LocalTransport> {
+    let workspace = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let target_directory = match std::env::var_os("CARGO_TARGET_DIR") {
+        Some(target_directory) => workspace.join(target_directory),
+        None => workspace.join("target"),
+    };
+    let server_binary = target_directory
+        .join(if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        })
+        .join("qubes-converter-server");
+    if !server_binary.is_file() {
+        eprintln!(
+            "Skipped: no server at {}, build it first with \"cargo build -p qubes-converter-server\"{}",
+            server_binary.display(),
+            if cfg!(debug_assertions) { "" } else { " --release" }
+        );
+        return None;
+    }
+    Some(LocalTransport {
+        server_binary,
+        stderr: true,
+    })
+}
+#[cfg(test)]
+fn test_directory() -> String {
+    std::env::temp_dir()
+        .join(format!("qubes_convert_test_{}", Uuid::new_v4()))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+#[cfg(test)]
+fn test_parameters() -> ConvertParameters {
+    ConvertParameters {
+        files: Vec::new(),
+        in_place: false,
+        archive: None,
+        passwords: Vec::new(),
+        file_passwords: HashMap::new(),
+        max_password_attempts: 0,
+        max_pages_converted_in_parallele: 1,
+        ocr: None,
+        audio_format: AudioFormat::Wav,
+        image_format: ImageFormat::Png,
+        file_image_formats: HashMap::new(),
+        video_audio: true,
+        html_single_page: false,
+        dpi: Resolution::Print.dpi(),
+        pages: Vec::new(),
+        pixel_format: PixelFormat::Rgba,
+        page_compression: PageCompression::Lossless,
+        output_profile: OutputProfile::Standard,
+        target: DISPOSABLE_TARGET.to_string(),
+        extension_targets: HashMap::new(),
+        parallel_servers: 1,
+        isolation: Isolation::Batch,
+        stderr: false,
+    }
+}
+#[cfg(test)]
+fn test_provenance() -> Provenance {
+    Provenance {
+        source_file: "test.txt".to_string(),
+        source_sha256: "0".repeat(64),
+        conversion_date: "2022-01-31T12:00:00Z".to_string(),
+        converter_version: "test".to_string(),
+        dpi: Resolution::Print.dpi(),
+        ocr_lang: None,
+    }
+}
+
+// Where the trusted version of the sample `path` is written: archives and mailboxes become a
+// directory.
+#[cfg(test)]
+fn trusted_output(temporary_directory: &str, file_base_name: &str, path: &Path) -> String {
+    let mimetype: mime::Mime = tree_magic::from_filepath(path)
+        .parse()
+        .expect("Incorrect detection of mimetype");
+    let extension = match (mimetype.type_(), mimetype.subtype().as_str()) {
+        (mime::AUDIO, _) => ".wav",
+        (mime::VIDEO, _) => ".mkv",
+        (mime::IMAGE, _) => ".png",
+        (mime::APPLICATION, "zip" | "gzip" | "x-tar" | "mbox") => "",
+        _ => ".pdf",
+    };
+    format!(
+        "{}/{}.trusted{}",
+        temporary_directory, file_base_name, extension
+    )
+}
+#[cfg(test)]
+fn remove_trusted_output(trusted_output: &str) {
+    if Path::new(trusted_output).is_dir() {
+        fs::remove_dir_all(trusted_output).unwrap();
+    } else {
+        fs::remove_file(trusted_output).unwrap();
+    }
+}
 
+// Fake server turning every file into a 2x1 image, except the ones containing "garbage": it
+// answers them with a message that does not exist.
 #[cfg(test)]
-const QREXEC_BINARY: &str = "target/release/qubes-converter-server";
+fn fake_server(
+    mut input: &mut dyn std::io::Read,
+    mut output: &mut dyn std::io::Write,
+) -> Result<(), ProtocolError> {
+    protocol::send_hello(&mut output)?;
+    protocol::receive_hello(&mut input)?;
+    let number_files = match ClientMessage::read_from(&mut input)? {
+        ClientMessage::Batch { number_files, .. } => number_files,
+        _ => return Err(ProtocolError::InvalidValue("expected a batch header")),
+    };
+    for _ in 0..number_files {
+        let content = match ClientMessage::read_from(&mut input)? {
+            ClientMessage::File { content, .. } => content,
+            _ => return Err(ProtocolError::InvalidValue("expected a file")),
+        };
+        if content == b"garbage" {
+            output.write_all(&[0xff])?;
+            continue;
+        }
+        ServerMessage::FileInfo {
+            number_pages: 1,
+            output_type: OutputType::Image,
+        }
+        .write_to(&mut output)?;
+        ServerMessage::Page {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Rgb,
+        }
+        .write_to(&mut output)?;
+        output.write_all(&[255, 0, 0, 0, 0, 255])?;
+    }
+    Ok(())
+}
+
+#[test]
+fn convert_with_fake_server_test() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    // With one server, the second one only converts the third file. Then each file gets a share,
+    // or a server.
+    for (parallel_servers, isolation, expected_connections) in [
+        (1, Isolation::Batch, 2),
+        (2, Isolation::Batch, 2),
+        (3, Isolation::Batch, 3),
+        (1, Isolation::File, 3),
+        (2, Isolation::File, 3),
+    ] {
+        let temporary_directory = test_directory();
+        fs::create_dir_all(format!("{}/archive", temporary_directory)).unwrap();
+        let mut files = Vec::new();
+        for (name, content) in [
+            ("first", "image"),
+            ("second", "garbage"),
+            ("third", "image"),
+        ] {
+            let file = format!("{}/{}.txt", temporary_directory, name);
+            fs::write(&file, content).unwrap();
+            files.push(file);
+        }
+        let parameters = ConvertParameters {
+            archive: Some(format!("{}/archive", temporary_directory)),
+            files: files.clone(),
+            pixel_format: PixelFormat::Rgb,
+            parallel_servers,
+            isolation,
+            stderr: true,
+            ..test_parameters()
+        };
+        let connections = Arc::new(Mutex::new(0));
+        let connections_clone = Arc::clone(&connections);
+        let transport = MemoryTransport::new(move |input, output| {
+            *connections_clone.lock().unwrap() += 1;
+            let _ = fake_server(input, output);
+        });
+        let (transmitter_convert_events, receiver_convert_events) = channel();
+        convert_all_files_with_transport(&transmitter_convert_events, parameters, &transport)
+            .unwrap();
+        drop(transmitter_convert_events);
+        let mut failures = Vec::new();
+        let mut converted_pages = Vec::new();
+        let mut converted_files = Vec::new();
+        for event in receiver_convert_events {
+            match event {
+                ConvertEvent::Failure { file, .. } => failures.push(file),
+                ConvertEvent::PageConverted { file, .. } => converted_pages.push(file),
+                ConvertEvent::FileConverted { file } => converted_files.push(file),
+                _ => {}
+            }
+        }
+        converted_pages.sort();
+        converted_files.sort();
+        // The server is replaced after the protocol violation, the third file is still converted.
+        assert_eq!(failures, [files[1].clone()]);
+        assert_eq!(converted_pages, [files[0].clone(), files[2].clone()]);
+        assert_eq!(converted_files, converted_pages);
+        assert_eq!(*connections.lock().unwrap(), expected_connections);
+        for (name, converted) in [("first", true), ("second", false), ("third", true)] {
+            let trusted_file = format!("{}/{}.trusted.png", temporary_directory, name);
+            assert_eq!(Path::new(&trusted_file).exists(), converted);
+            let archived_file = format!("{}/archive/{}.txt", temporary_directory, name);
+            assert_eq!(Path::new(&archived_file).exists(), converted);
+        }
+        fs::remove_dir_all(&temporary_directory).unwrap();
+    }
+}
+
+#[test]
+fn isolation_test() {
+    let files = ["a/1.pdf", "a/2.pdf", "b/3.pdf", "a/4.pdf"].map(str::to_string);
+    assert_eq!(Isolation::Batch.group_size(&files), 4);
+    assert_eq!(Isolation::Directory.group_size(&files), 2);
+    assert_eq!(Isolation::Directory.group_size(&files[2..]), 1);
+    assert_eq!(Isolation::File.group_size(&files), 1);
+    assert_eq!(Isolation::File.group_size(&[]), 0);
+    assert_eq!("directory".parse(), Ok(Isolation::Directory));
+    assert!("qube".parse::<Isolation>().is_err());
+}
 
 #[test]
 fn convert_all_in_one_integration_test() {
     let _ = env_logger::builder().is_test(true).try_init();
+    let transport = match test_transport() {
+        Some(transport) => transport,
+        None => return,
+    };
     let mut files_that_must_exist = Vec::new();
-    let temporary_directory = format!("/home/user/.temp_qubes_convert_{}", Uuid::new_v4());
+    let temporary_directory = test_directory();
     fs::create_dir_all(&temporary_directory).unwrap();
     let mut files = Vec::new();
-    for entry in glob("tests/files/*").expect("Failed to read glob pattern") {
+    for entry in glob(&format!("{}/*", TEST_FILES)).expect("Failed to read glob pattern") {
         match entry {
             Ok(path) => {
                 println!("{:?}", path.display());
@@ -47,17 +310,8 @@ fn convert_all_in_one_integration_test() {
                 );
                 fs::copy(&path, &filename).unwrap();
                 files.push(filename);
-                let mimetype: mime::Mime = tree_magic::from_filepath(&path)
-                    .parse()
-                    .expect("Incorrect detection of mimetype");
-                let mut expected_output_filename =
-                    format!("{}/{}.trusted.", &temporary_directory, &file_base_name);
-                expected_output_filename.push_str(match (mimetype.type_(), mimetype.subtype()) {
-                    (mime::AUDIO, _) => panic!("Audio convert not implemented"),
-                    (mime::VIDEO, _) => panic!("Video convert not implemented"),
-                    (mime::IMAGE, _) => "png",
-                    _ => "pdf",
-                });
+                let expected_output_filename =
+                    trusted_output(&temporary_directory, file_base_name, &path);
                 match fs::remove_file(&expected_output_filename) {
                     Ok(_) => panic!("Converted file already exist before beginning of the tests !"),
                     Err(_) => {}
@@ -71,19 +325,17 @@ fn convert_all_in_one_integration_test() {
         }
     }
     let parameters = ConvertParameters {
-        in_place: false,
         archive: Some(format!("{}/", temporary_directory)),
         files,
-        default_password: "toor".to_string(),
-        max_pages_converted_in_parallele: 1,
-        ocr: None,
+        passwords: vec!["toor".to_string()],
         stderr: true,
+        ..test_parameters()
     };
     let (transmitter_convert_events, _receiver_convert_events) = channel();
-    convert_all_files(&transmitter_convert_events, parameters).unwrap();
+    convert_all_files_with_transport(&transmitter_convert_events, parameters, &transport).unwrap();
     for file_that_must_exist in files_that_must_exist {
         assert_eq!(true, std::path::Path::new(&file_that_must_exist.0).exists());
-        fs::remove_file(&file_that_must_exist.0).unwrap();
+        remove_trusted_output(&file_that_must_exist.0);
     }
     fs::remove_dir_all(&temporary_directory).unwrap();
 }
@@ -91,9 +343,13 @@ fn convert_all_in_one_integration_test() {
 #[test]
 fn convert_one_by_one_integration_test() {
     let _ = env_logger::builder().is_test(true).try_init();
-    let temporary_directory = format!("/home/user/.temp_qubes_convert_{}", Uuid::new_v4());
+    let transport = match test_transport() {
+        Some(transport) => transport,
+        None => return,
+    };
+    let temporary_directory = test_directory();
     fs::create_dir_all(&temporary_directory).unwrap();
-    for entry in glob("tests/files/*").expect("Failed to read glob pattern") {
+    for entry in glob(&format!("{}/*", TEST_FILES)).expect("Failed to read glob pattern") {
         match entry {
             Ok(path) => {
                 println!("{:?}", path.display());
@@ -107,40 +363,34 @@ fn convert_one_by_one_integration_test() {
                     ),
                 )
                 .unwrap();
-                let mimetype: mime::Mime = tree_magic::from_filepath(&path)
-                    .parse()
-                    .expect("Incorrect detection of mimetype");
-                let mut expected_output_filename =
-                    format!("{}/{}.trusted.", &temporary_directory, &file_base_name);
-                expected_output_filename.push_str(match (mimetype.type_(), mimetype.subtype()) {
-                    (mime::AUDIO, _) => panic!("Audio convert not implemented"),
-                    (mime::VIDEO, _) => panic!("Video convert not implemented"),
-                    (mime::IMAGE, _) => "png",
-                    _ => "pdf",
-                });
+                let expected_output_filename =
+                    trusted_output(&temporary_directory, file_base_name, &path);
                 match fs::remove_file(&expected_output_filename) {
                     Ok(_) => panic!("Converted file already exist before beginning of the tests !"),
                     Err(_) => {}
                 }
                 let parameters = ConvertParameters {
-                    in_place: false,
                     archive: Some(format!("{}/", temporary_directory)),
                     files: vec![format!(
                         "{}/{}.{}",
                         &temporary_directory, &file_base_name, &file_extension
                     )],
-                    default_password: "toor".to_string(),
-                    max_pages_converted_in_parallele: 1,
-                    ocr: None,
+                    passwords: vec!["toor".to_string()],
                     stderr: true,
+                    ..test_parameters()
                 };
                 let (transmitter_convert_events, _receiver_convert_events) = channel();
-                convert_all_files(&transmitter_convert_events, parameters).unwrap();
+                convert_all_files_with_transport(
+                    &transmitter_convert_events,
+                    parameters,
+                    &transport,
+                )
+                .unwrap();
                 assert_eq!(
                     true,
                     std::path::Path::new(&expected_output_filename).exists()
                 );
-                fs::remove_file(&expected_output_filename).unwrap();
+                remove_trusted_output(&expected_output_filename);
             }
             Err(_e) => panic!("glob error"),
         }
@@ -148,40 +398,64 @@ fn convert_one_by_one_integration_test() {
     fs::remove_dir_all(&temporary_directory).unwrap();
 }
 
+#[test]
+fn convert_html_single_page_integration_test() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let transport = match test_transport() {
+        Some(transport) => transport,
+        None => return,
+    };
+    let temporary_directory = test_directory();
+    fs::create_dir_all(&temporary_directory).unwrap();
+    let file = format!("{}/html.html", temporary_directory);
+    fs::copy(format!("{}/html.html", TEST_FILES), &file).unwrap();
+    let parameters = ConvertParameters {
+        archive: Some(format!("{}/", temporary_directory)),
+        files: vec![file],
+        html_single_page: true,
+        stderr: true,
+        ..test_parameters()
+    };
+    let (transmitter_convert_events, _receiver_convert_events) = channel();
+    convert_all_files_with_transport(&transmitter_convert_events, parameters, &transport).unwrap();
+    // The whole document is one image, scaled down when it is too long.
+    let (width, height) =
+        image::image_dimensions(format!("{}/html.trusted.png", temporary_directory)).unwrap();
+    assert!(width <= protocol::MAX_PAGE_WIDTH && height <= protocol::MAX_PAGE_HEIGHT);
+    fs::remove_dir_all(&temporary_directory).unwrap();
+}
+
 #[test]
 fn convert_one_big_integration_test() {
     let _ = env_logger::builder().is_test(true).try_init();
-    let temporary_directory = format!("/home/user/.temp_qubes_convert_{}", Uuid::new_v4());
+    let transport = match test_transport() {
+        Some(transport) => transport,
+        None => return,
+    };
+    let temporary_directory = test_directory();
     fs::create_dir_all(&temporary_directory).unwrap();
     let file = "IPCC_AR6_WGI_Full_Report.pdf";
-    let path = format!("tests/files/{}", file);
+    let path = format!("{}/{}", TEST_FILES, file);
     fs::copy(&path, &format!("{}/{}", &temporary_directory, &file)).unwrap();
-    let mimetype: mime::Mime = tree_magic::from_filepath(std::path::Path::new(&path))
-        .parse()
-        .expect("Incorrect detection of mimetype");
-    let mut expected_output_filename =
-        format!("{}/IPCC_AR6_WGI_Full_Report.trusted.", &temporary_directory);
-    expected_output_filename.push_str(match (mimetype.type_(), mimetype.subtype()) {
-        (mime::AUDIO, _) => panic!("Audio convert not implemented"),
-        (mime::VIDEO, _) => panic!("Video convert not implemented"),
-        (mime::IMAGE, _) => "png",
-        _ => "pdf",
-    });
+    let expected_output_filename = trusted_output(
+        &temporary_directory,
+        "IPCC_AR6_WGI_Full_Report",
+        Path::new(&path),
+    );
     match fs::remove_file(&expected_output_filename) {
         Ok(_) => panic!("Converted file already exist before beginning of the tests !"),
         Err(_) => {}
     }
     let parameters = ConvertParameters {
-        in_place: false,
         archive: Some(format!("{}/", temporary_directory)),
         files: vec![format!("{}/{}", &temporary_directory, &file)],
-        default_password: "toor".to_string(),
+        passwords: vec!["toor".to_string()],
         max_pages_converted_in_parallele: 4,
-        ocr: None,
         stderr: true,
+        ..test_parameters()
     };
     let (transmitter_convert_events, _receiver_convert_events) = channel();
-    convert_all_files(&transmitter_convert_events, parameters).unwrap();
+    convert_all_files_with_transport(&transmitter_convert_events, parameters, &transport).unwrap();
     assert_eq!(
         true,
         std::path::Path::new(&expected_output_filename).exists()
@@ -190,14 +464,143 @@ fn convert_one_big_integration_test() {
     fs::remove_dir_all(&temporary_directory).unwrap();
 }
 
+/// Usual rendering resolutions.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Resolution {
+    Screen,
+    Print,
+    Archival,
+}
+impl Resolution {
+    pub const fn dpi(self) -> u16 {
+        match self {
+            Self::Screen => 75,
+            Self::Print => 150,
+            Self::Archival => 300,
+        }
+    }
+}
+impl FromStr for Resolution {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "screen" => Ok(Self::Screen),
+            "print" => Ok(Self::Print),
+            "archival" => Ok(Self::Archival),
+            _ => Err(format!("Unknown resolution: {}", value)),
+        }
+    }
+}
+
+/// Standard trusted PDFs may be read by any viewer, PDF/A-2b ones are meant for archival: they
+/// embed their color profile and identify themselves in XMP metadata. Text documents need an
+/// installed font with a glyph for each of their characters.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum OutputProfile {
+    Standard,
+    PdfA2b,
+}
+impl FromStr for OutputProfile {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "standard" => Ok(Self::Standard),
+            "pdfa-2b" => Ok(Self::PdfA2b),
+            _ => Err(format!("Unknown output profile: {}", value)),
+        }
+    }
+}
+
+/// Which files share a disposable qube. A malicious file can tamper with the rendering of the
+/// files converted after it in the same qube, every new qube costs its startup time though.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Isolation {
+    /// One qube for the whole batch, or for each share of it.
+    Batch,
+    /// One qube for consecutive files of the same directory.
+    Directory,
+    /// One qube for each file.
+    File,
+}
+impl Isolation {
+    // How many of `files`, from the first one, may be converted by the same server.
+    fn group_size(self, files: &[String]) -> usize {
+        match (self, files.first()) {
+            (_, None) => 0,
+            (Self::Batch, Some(_)) => files.len(),
+            (Self::Directory, Some(first_file)) => {
+                let directory = Path::new(first_file).parent();
+                files
+                    .iter()
+                    .take_while(|file| Path::new(file).parent() == directory)
+                    .count()
+            }
+            (Self::File, Some(_)) => 1,
+        }
+    }
+}
+impl FromStr for Isolation {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "batch" => Ok(Self::Batch),
+            "directory" => Ok(Self::Directory),
+            "file" => Ok(Self::File),
+            _ => Err(format!("Unknown isolation: {}", value)),
+        }
+    }
+}
+
+/// How page images are stored in trusted PDFs. Black and white pages are always lossless, at one
+/// bit per pixel they are much smaller than both.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum PageCompression {
+    Lossless,
+    /// `quality` goes from 1 to 100.
+    Jpeg {
+        quality: u8,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub struct ConvertParameters {
     pub files: Vec<String>,
     pub in_place: bool,
     pub archive: Option<String>,
-    pub default_password: String,
+    /// Tried in order on every protected file, after its entry in `file_passwords`.
+    pub passwords: Vec<String>,
+    /// Known password of some files, keyed by their path.
+    pub file_passwords: HashMap<String, String>,
+    pub max_password_attempts: u8,
     pub max_pages_converted_in_parallele: u8,
     pub ocr: Option<String>,
+    pub audio_format: AudioFormat,
+    /// Format of still images, unless their entry in `file_image_formats` says otherwise.
+    pub image_format: ImageFormat,
+    /// Format of some still images, keyed by their path.
+    pub file_image_formats: HashMap<String, ImageFormat>,
+    /// Keep the sound of videos.
+    pub video_audio: bool,
+    /// Render HTML and Markdown as one long image instead of a PDF.
+    pub html_single_page: bool,
+    /// Resolution documents are rendered at, see `Resolution` for the usual values.
+    pub dpi: u16,
+    /// Pages of paginated documents to convert, every page when empty.
+    pub pages: Vec<PageRange>,
+    /// Pixels of the pages: RGBA, RGB, 8 bits gray or black and white.
+    pub pixel_format: PixelFormat,
+    /// Compression of the pages of trusted PDFs.
+    pub page_compression: PageCompression,
+    pub output_profile: OutputProfile,
+    /// Qrexec target of the servers, unless the extension of the file has an entry in
+    /// `extension_targets`. See `check_target`.
+    pub target: String,
+    /// Qrexec target of some file types, keyed by their lowercase extension.
+    pub extension_targets: HashMap<String, String>,
+    /// How many servers, so disposable qubes, convert files at the same time.
+    pub parallel_servers: u8,
+    /// Which files share a server, see `Isolation`.
+    pub isolation: Isolation,
     pub stderr: bool,
 }
 #[derive(Debug)]
@@ -208,6 +611,9 @@ pub enum ConvertEvent {
     FileInfo {
         output_type: OutputType,
         number_pages: u16,
+        /// Rough size of the trusted file in bytes, when it can be guessed before any page is
+        /// received.
+        estimated_size: Option<u64>,
         file: String,
     },
     PageConverted {
@@ -221,85 +627,138 @@ pub enum ConvertEvent {
         file: String,
         message: String,
     },
+    /// The server can not open `file` without a password. The answer must be sent through
+    /// `reply`, `None` gives up on this file. The conversion waits until an answer is sent or
+    /// `reply` is dropped.
+    PasswordRequired {
+        file: String,
+        attempt: u8,
+        reply: Sender<Option<String>>,
+    },
 }
-pub fn default_archive_folder() -> String {
-    format!(
+fn home_directory() -> Result<PathBuf, ConvertError> {
+    Ok(home::home_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?)
+}
+pub fn default_archive_folder() -> Result<String, ConvertError> {
+    Ok(format!(
         "{}/QubesUntrusted/",
-        home::home_dir().unwrap().to_str().unwrap()
-    )
+        path_to_str(&home_directory()?)?
+    ))
+}
+fn limit_exceeded(message: &str) -> ConvertError {
+    ConvertError::LimitExceeded(message.to_string())
+}
+// Rough size of a PDF of `number_pages` A4 pages, from the usual compression ratios of rendered
+// documents. Only meant to warn before huge outputs.
+fn estimate_pdf_size(number_pages: u16, parameters: &ConvertParameters) -> u64 {
+    let dpi = f64::from(parameters.dpi);
+    let pixels_per_page = 8.27 * dpi * 11.69 * dpi;
+    let bytes_per_pixel = match (parameters.pixel_format, parameters.page_compression) {
+        (PixelFormat::Bilevel, _) => 0.01,
+        (PixelFormat::Gray, PageCompression::Jpeg { quality }) => {
+            0.02 + 0.2 * f64::from(quality) / 100.0
+        }
+        (_, PageCompression::Jpeg { quality }) => 0.03 + 0.3 * f64::from(quality) / 100.0,
+        (PixelFormat::Gray, PageCompression::Lossless) => 0.35,
+        (_, PageCompression::Lossless) => 1.0,
+    };
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let size = (pixels_per_page * bytes_per_pixel * f64::from(number_pages)) as u64;
+    size
 }
-fn convert_one_page(
-    mpsc_sender: &Sender<ConvertEvent>,
-    source_file: &str,
-    process_stdout: &mut ChildStdout,
-    temporary_file_base_page: &str,
-    output_type: OutputType,
-    ocr: &Option<String>,
-) -> Result<(String, Option<Child>), Box<dyn std::error::Error>> {
-    debug!("reading size and output type from server");
-    let mut buffer_size = [0; 2 + 2];
-    process_stdout.read_exact(&mut buffer_size)?;
-    let width_raw = buffer_size[..2].try_into().unwrap();
-    let height_raw = buffer_size[2..4].try_into().unwrap();
-    let width = u32::from(u16::from_le_bytes(width_raw));
-    let height = u32::from(u16::from_le_bytes(height_raw));
-    if height as usize > MAX_IMG_HEIGHT
-        || width as usize > MAX_IMG_WIDTH
-        || width as usize * height as usize * 4 > MAX_IMG_SIZE
-    {
-        let failure_message = "Max image size exceeded: Probably DOS attempt";
-        mpsc_sender.send(ConvertEvent::Failure {
-            file: source_file.to_string(),
-            message: failure_message.to_string(),
-        })?;
-        panic!("{}", failure_message);
-    }
-
-    debug!("reading page data from server");
-    let mut buffer_page = vec![0; (height * width * 4) as usize];
-    process_stdout.read_exact(&mut buffer_page)?;
 
-    let png_file_path = format!("{}.png", temporary_file_base_page);
-    let image = image::RgbaImage::from_raw(width, height, buffer_page).unwrap();
-    image.save(&png_file_path).unwrap();
+#[test]
+fn estimate_pdf_size_test() {
+    let mut parameters = ConvertParameters {
+        pixel_format: PixelFormat::Rgb,
+        ..test_parameters()
+    };
+    let lossless = estimate_pdf_size(10, &parameters);
+    assert_eq!(estimate_pdf_size(0, &parameters), 0);
+    parameters.page_compression = PageCompression::Jpeg { quality: 75 };
+    let jpeg = estimate_pdf_size(10, &parameters);
+    parameters.pixel_format = PixelFormat::Bilevel;
+    let bilevel = estimate_pdf_size(10, &parameters);
+    assert!(bilevel < jpeg && jpeg < lossless);
+    parameters.dpi = Resolution::Archival.dpi();
+    assert!(estimate_pdf_size(10, &parameters) > bilevel * 3);
+}
 
-    match output_type {
-        OutputType::Pdf => {
-            let pdf_file_path = format!("{}.pdf", temporary_file_base_page);
-            let mut process_name = "gm";
-            let mut process_args = vec!["convert", &png_file_path, &pdf_file_path];
-            if let Some(ocr_lang) = ocr {
-                process_name = "tesseract";
-                process_args = vec![
-                    &png_file_path,
-                    temporary_file_base_page,
-                    "-l",
-                    ocr_lang,
-                    "--dpi",
-                    "70",
-                    "pdf",
-                ];
-            }
-            let convert_to_pdf_process = Command::new(process_name)
-                .args(&process_args)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-                .expect("Unable to launch the 'convert to pdf' process (tesseract/gm)");
-            Ok((pdf_file_path, Some(convert_to_pdf_process)))
+// Shared by pages and video frames.
+fn image_size_is_valid(width: u32, height: u32, bytes_per_pixel: usize) -> bool {
+    height as usize <= MAX_IMG_HEIGHT
+        && width as usize <= MAX_IMG_WIDTH
+        && width as usize * height as usize * bytes_per_pixel <= MAX_IMG_SIZE
+}
+// The server must send pages in the format the client asked for.
+fn read_page_header(
+    process_stdout: &mut FromServer,
+    pixel_format: PixelFormat,
+) -> Result<(u32, u32), ConvertError> {
+    match ServerMessage::read_from(process_stdout)? {
+        ServerMessage::Page {
+            width,
+            height,
+            format,
+        } if format == pixel_format => Ok((width, height)),
+        ServerMessage::Page { .. } => {
+            Err(ProtocolError::InvalidValue("unexpected pixel format").into())
         }
-        OutputType::Image => Ok((png_file_path, None)),
+        ServerMessage::Failure(failure) => Err(failure.into()),
+        _ => Err(ProtocolError::InvalidValue("expected a page").into()),
     }
 }
-pub fn list_ocr_langs() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+// PNG color type and bit depth storing pixels of `pixel_format` as they are.
+fn png_color(pixel_format: PixelFormat) -> (png::ColorType, png::BitDepth) {
+    match pixel_format {
+        PixelFormat::Rgba => (png::ColorType::Rgba, png::BitDepth::Eight),
+        PixelFormat::Rgb => (png::ColorType::Rgb, png::BitDepth::Eight),
+        PixelFormat::Gray => (png::ColorType::Grayscale, png::BitDepth::Eight),
+        PixelFormat::Bilevel => (png::ColorType::Grayscale, png::BitDepth::One),
+    }
+}
+// Provenance goes into UTF-8 text chunks.
+fn write_png(
+    png_file_path: &str,
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+    pixels: &[u8],
+    provenance: Option<&Provenance>,
+) -> Result<(), ConvertError> {
+    let mut encoder =
+        png::Encoder::new(BufWriter::new(File::create(png_file_path)?), width, height);
+    let (color, depth) = png_color(pixel_format);
+    encoder.set_color(color);
+    encoder.set_depth(depth);
+    for (key, value) in provenance.map(Provenance::entries).unwrap_or_default() {
+        encoder.add_itxt_chunk(key.to_string(), value)?;
+    }
+    encoder.write_header()?.write_image_data(pixels)?;
+    Ok(())
+}
+pub fn list_ocr_langs() -> Result<Vec<String>, ConvertError> {
     let command_output = Command::new("tesseract")
         .arg("--list-langs")
         .output()
-        .expect(
-            "Unable to list languages supported by tesseract. Tesseract is probably not installed.",
-        );
+        .map_err(|e| {
+            ConvertError::tool_failed(
+                "tesseract",
+                format!(
+                    "unable to list its languages, it is probably not installed: {}",
+                    e
+                ),
+            )
+        })?;
+    if !command_output.status.success() {
+        return Err(ConvertError::tool_failed(
+            "tesseract",
+            String::from_utf8_lossy(&command_output.stderr),
+        ));
+    }
     let mut result = Vec::new();
-    let stdout = String::from_utf8(command_output.stdout)?;
+    let stdout = String::from_utf8_lossy(&command_output.stdout);
     let mut header = true;
     for line in stdout.lines() {
         if header {
@@ -311,264 +770,565 @@ pub fn list_ocr_langs() -> Result<Vec<String>, Box<dyn std::error::Error>> {
     Ok(result)
 }
 
-fn convert_all_pages(
+fn ask_password(
     mpsc_sender: &Sender<ConvertEvent>,
-    process_stdout: &mut ChildStdout,
     source_file: &str,
+    attempt: u8,
+) -> Result<Option<String>, ConvertError> {
+    let (reply, answer) = channel();
+    mpsc_sender.send(ConvertEvent::PasswordRequired {
+        file: source_file.to_string(),
+        attempt,
+        reply,
+    })?;
+    Ok(answer.recv().unwrap_or(None))
+}
+/// Receive the conversion of one document, from its `FileInfo` on, and write it to
+/// `output_base` followed by the extension of its output type. `label` names the document in the
+/// events. `depth` counts the archives the document is nested in, `provenance` is the one of the
+/// file they all come from.
+#[allow(clippy::too_many_arguments)]
+fn receive_document(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdin: &Mutex<ToServer>,
+    process_stdout: &mut FromServer,
+    label: &str,
     temporary_directory: &str,
     parameters: &ConvertParameters,
-    output_type: OutputType,
-    number_pages: u16,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-    let mut output_pages = Vec::new();
-    let mut all_pages_convert_process: HashMap<u16, (String, Option<Child>)> = HashMap::new();
-
-    // Tesseract process require gigantic amount of memory.
-    // Memory starving tesseract process will slow down everything and result in much MUCH worse
-    // performance (freezing, some kind of deadlock and crashing included).
-    // So the optimal amount of concurrent tesseract seems to be a computation between number of
-    // CPU physical core available and memory available.
-    //
-    // On my particular setup (highend gaming setup from late 2017, 8 physical core and 32 go ram)
-    // , "3" seems to be the best number for the fastest conversion.
-    // This number will vary depending on the hardware.
-    // In case of doubt, less tesseract process is better than more tesseract process.
-    let maximum_number_process = parameters.max_pages_converted_in_parallele;
-
-    for page in 0..number_pages {
-        while all_pages_convert_process.len() >= maximum_number_process.into() {
-            for page_id in all_pages_convert_process
-                .keys()
-                .copied()
-                .collect::<Vec<u16>>()
-            {
-                let mut page_convert_process = all_pages_convert_process.remove(&page_id).unwrap();
-                let page_path = page_convert_process.0.to_string();
-                let mut page_converted = true;
-                if let Some(ref mut process) = page_convert_process.1 {
-                    if process.try_wait().expect("'try_wait' failed").is_none() {
-                        all_pages_convert_process.insert(page_id, page_convert_process);
-                        page_converted = false;
-                    }
-                }
-                if page_converted {
-                    debug!("Sending page converted information");
-                    output_pages.push(page_path);
-                    mpsc_sender.send(ConvertEvent::PageConverted {
-                        file: source_file.to_string(),
-                        page: page_id,
-                    })?;
-                }
-            }
-            if all_pages_convert_process.keys().len() >= maximum_number_process.into() {
-                debug!("sleeping");
-                let sleep_time = time::Duration::from_millis(200);
-                thread::sleep(sleep_time);
+    provenance: &Provenance,
+    output_base: &str,
+    depth: u8,
+) -> Result<(), ConvertError> {
+    let (number_pages, output_type) = loop {
+        match ServerMessage::read_from(process_stdout)? {
+            ServerMessage::FileInfo {
+                number_pages,
+                output_type,
+            } => break (number_pages, output_type),
+            ServerMessage::PasswordRequest { file, attempt } => {
+                debug!("Server requests a password for file {}", file);
+                let password = ask_password(mpsc_sender, label, attempt)?;
+                ClientMessage::Password { password }.write_to(&mut *lock_stdin(process_stdin))?;
             }
+            ServerMessage::Failure(failure) => return Err(failure.into()),
+            _ => return Err(ProtocolError::InvalidValue("expected file information").into()),
         }
-        let temporary_file_base_page = format!("{}.{}", temporary_directory, page);
-        let converted_page = convert_one_page(
-            mpsc_sender,
-            source_file,
-            process_stdout,
-            &temporary_file_base_page,
-            output_type,
-            &parameters.ocr,
-        )?;
-        all_pages_convert_process.insert(page, converted_page);
+    };
+    if number_pages > MAX_PAGES {
+        debug!("Number of page sended by the server: {}", number_pages);
+        return Err(limit_exceeded(
+            "Max page number exceeded: Probably DOS attempt",
+        ));
     }
-    for (page_id, page_convert_process) in all_pages_convert_process {
-        if let Some(mut process) = page_convert_process.1 {
-            process.wait()?;
+    // Pages of the original document, numbered from 1, sent for this PDF.
+    let source_pages = if output_type == OutputType::Pdf {
+        let total_pages = match ServerMessage::read_from(process_stdout)? {
+            ServerMessage::PageSelection { total_pages } => total_pages,
+            ServerMessage::Failure(failure) => return Err(failure.into()),
+            _ => return Err(ProtocolError::InvalidValue("expected the page selection").into()),
+        };
+        let source_pages = protocol::selected_pages(&parameters.pages, total_pages);
+        if source_pages.len() != usize::from(number_pages) {
+            return Err(ProtocolError::InvalidValue("pages do not match the selection").into());
         }
-        output_pages.push(page_convert_process.0.to_string());
-        mpsc_sender.send(ConvertEvent::PageConverted {
-            file: source_file.to_string(),
-            page: page_id,
-        })?;
+        source_pages
+    } else {
+        Vec::new()
+    };
+    let output_file = match output_type {
+        OutputType::Archive => output_base.to_string(),
+        OutputType::Audio => format!("{}.{}", output_base, parameters.audio_format.extension()),
+        OutputType::Image if number_pages == 1 => {
+            format!("{}.{}", output_base, parameters.image_format.extension())
+        }
+        _ => format!("{}.{}", output_base, output_type.extension()),
+    };
+    if output_type == OutputType::Image && number_pages == 0 {
+        return Err(ProtocolError::InvalidValue("image without any frame").into());
     }
-    Ok(output_pages)
-}
+    mpsc_sender.send(ConvertEvent::FileInfo {
+        file: label.to_string(),
+        output_type,
+        number_pages,
+        estimated_size: (output_type == OutputType::Pdf)
+            .then(|| estimate_pdf_size(number_pages, parameters)),
+    })?;
 
+    match output_type {
+        OutputType::Audio => audio::receive_audio(
+            mpsc_sender,
+            process_stdout,
+            label,
+            &format!("{}/audio", temporary_directory),
+            &output_file,
+            number_pages,
+            parameters.audio_format,
+        )?,
+        OutputType::Video => video::receive_video(
+            mpsc_sender,
+            process_stdout,
+            label,
+            &format!("{}/video", temporary_directory),
+            &output_file,
+            number_pages,
+        )?,
+        OutputType::Archive => archive::receive_archive(
+            mpsc_sender,
+            process_stdin,
+            process_stdout,
+            label,
+            temporary_directory,
+            parameters,
+            provenance,
+            &output_file,
+            number_pages,
+            depth,
+        )?,
+        OutputType::Email => email::receive_email(
+            mpsc_sender,
+            process_stdin,
+            process_stdout,
+            label,
+            temporary_directory,
+            parameters,
+            provenance,
+            output_base,
+            number_pages,
+            depth,
+        )?,
+        OutputType::Image if number_pages > 1 => animation::receive_animation(
+            mpsc_sender,
+            process_stdout,
+            label,
+            &output_file,
+            number_pages,
+            parameters.pixel_format,
+            provenance,
+        )?,
+        OutputType::Text => text::receive_text(
+            mpsc_sender,
+            process_stdout,
+            label,
+            &output_file,
+            number_pages,
+            parameters.output_profile,
+            provenance,
+        )?,
+        OutputType::Image => still_image::receive_image(
+            mpsc_sender,
+            process_stdout,
+            label,
+            &format!("{}/image", temporary_directory),
+            &output_file,
+            parameters.pixel_format,
+            parameters.image_format,
+            provenance,
+        )?,
+        OutputType::Pdf => document::receive_document(
+            mpsc_sender,
+            process_stdout,
+            label,
+            temporary_directory,
+            &output_file,
+            number_pages,
+            &source_pages,
+            parameters,
+            provenance,
+        )?,
+    }
+    Ok(())
+}
+// Files are named by strings in the events, a path that is not UTF-8 can not be reported.
+fn path_to_str(path: &Path) -> Result<&str, ConvertError> {
+    path.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a UTF-8 path", path.display()),
+        )
+        .into()
+    })
+}
+// A panic while the lock is held can not leave the pipe in a worse state than an error would.
+fn lock_stdin(process_stdin: &Mutex<ToServer>) -> MutexGuard<'_, ToServer> {
+    process_stdin.lock().unwrap_or_else(PoisonError::into_inner)
+}
+/// Receive the trusted version of `source_file`, next to it.
 fn convert_one_file(
     mpsc_sender: &Sender<ConvertEvent>,
-    process_stdout: &mut ChildStdout,
+    process_stdin: &Mutex<ToServer>,
+    process_stdout: &mut FromServer,
     source_file: &str,
     temporary_directory: &str,
     parameters: &ConvertParameters,
-    archive_path: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), ConvertError> {
     debug!("BEGIN CONVERT ONE FILE: {}", source_file);
-    let mut buffer_pages_and_type = vec![0_u8; 2 + 1];
-    process_stdout.read_exact(&mut buffer_pages_and_type)?;
-    let number_pages_raw = buffer_pages_and_type[..2].try_into()?;
-    let number_pages = u16::from_le_bytes(number_pages_raw);
-    if number_pages > MAX_PAGES {
-        debug!("Number of page sended by the server: {}", number_pages);
-        let failure_message = "Max page number exceeded: Probably DOS attempt";
-        mpsc_sender.send(ConvertEvent::Failure {
-            file: source_file.to_string(),
-            message: failure_message.to_string(),
-        })?;
-        panic!("{}", failure_message);
-    }
     let source_file_path = fs::canonicalize(source_file)?;
-    let source_file_basename = source_file_path.file_stem().unwrap().to_str().unwrap();
-    let empty_extension = OsString::new();
-    let source_file_extension = source_file_path
-        .extension()
-        .unwrap_or(&empty_extension)
-        .to_str()
-        .unwrap();
-    let source_directory = source_file_path.parent().unwrap().to_str().unwrap();
-    let mut output_file = format!("{}/{}.trusted.", source_directory, source_file_basename);
-    let output_type = OutputType::try_from(*buffer_pages_and_type.get(2).unwrap())?;
-    output_file.push_str(output_type.extension());
-    if output_type == OutputType::Image && number_pages != 1 {
-        let failure_message = "Image can only be 1 page. Abording.";
-        mpsc_sender.send(ConvertEvent::Failure {
-            file: source_file.to_string(),
-            message: failure_message.to_string(),
-        })?;
-        panic!("{}", failure_message);
-    }
-    mpsc_sender.send(ConvertEvent::FileInfo {
-        file: source_file.to_string(),
-        output_type,
-        number_pages,
-    })?;
-
-    let output_pages = convert_all_pages(
+    let source_file_basename =
+        path_to_str(Path::new(source_file_path.file_stem().unwrap_or_default()))?;
+    let source_directory = path_to_str(source_file_path.parent().unwrap_or(Path::new("/")))?;
+    // Computed before anything is received, nested documents share it.
+    let provenance = Provenance::new(&source_file_path, parameters)?;
+    receive_document(
         mpsc_sender,
+        process_stdin,
         process_stdout,
         source_file,
         temporary_directory,
         parameters,
-        output_type,
-        number_pages,
+        &provenance,
+        &format!("{}/{}.trusted", source_directory, source_file_basename),
+        0,
     )?;
-
-    debug!("CONVERTED ALL PAGES");
-    match output_type {
-        OutputType::Image => {
-            fs::copy(output_pages.get(0).unwrap(), output_file)?;
-        }
-        OutputType::Pdf => {
-            let mut pdftk_args = output_pages;
-            pdftk_args.push("cat".to_string());
-            pdftk_args.push("output".to_string());
-            pdftk_args.push(output_file);
-            let command_output = Command::new("pdftk")
-                .args(&pdftk_args)
-                .output()
-                .expect("Unable to launch pdftk process");
-            if !command_output.status.success() {
-                let failure_message =
-                    "pdftk failed. Probable cause is 'out of space'. Check with 'df -h'";
-                mpsc_sender.send(ConvertEvent::Failure {
-                    file: source_file.to_string(),
-                    message: failure_message.to_string(),
-                })?;
-                panic!("{}", failure_message);
-            }
-        }
-    }
     mpsc_sender.send(ConvertEvent::FileConverted {
         file: source_file.to_string(),
     })?;
+    debug!("END CONVERT ONE FILE: {}", source_file);
+    Ok(())
+}
+/// Remove the original of a converted file, or move it to `archive_path`.
+fn archive_source_file(
+    source_file: &str,
+    parameters: &ConvertParameters,
+    archive_path: &str,
+) -> Result<(), ConvertError> {
+    let source_file_path = fs::canonicalize(source_file)?;
     if parameters.in_place {
         fs::remove_file(source_file_path)?;
     } else {
-        let archive_file = format!(
-            "{}{}.{}",
-            &archive_path, &source_file_basename, &source_file_extension
-        );
-        debug!("archiving {:?} to {}", source_file_path, &archive_file);
+        let archive_file = Path::new(archive_path)
+            .join(source_file_path.file_name().unwrap_or_default())
+            .into_os_string();
+        debug!("archiving {:?} to {:?}", source_file_path, &archive_file);
         fs::copy(&source_file_path, &archive_file)?;
         fs::remove_file(&source_file_path)?;
     }
-    debug!("END CONVERT ONE FILE: {}", source_file);
     Ok(())
 }
+
+// Entries of `map` keyed by the canonical path of their file. Files that do not exist are left out.
+fn by_canonical_path<T: Clone>(map: &HashMap<String, T>) -> HashMap<PathBuf, T> {
+    map.iter()
+        .filter_map(|(file, value)| {
+            fs::canonicalize(file)
+                .ok()
+                .map(|path| (path, value.clone()))
+        })
+        .collect()
+}
+
+// What the files of a batch share, whatever server converts them.
+struct Batch<'a> {
+    parameters: &'a ConvertParameters,
+    file_passwords: HashMap<PathBuf, String>,
+    file_image_formats: HashMap<PathBuf, ImageFormat>,
+    temporary_directory: String,
+    archive_path: String,
+    transport: &'a dyn Transport,
+}
+
+/// Convert `files` with a new server, `first_file_id` numbers the first one. A file that can not
+/// be converted is reported and the next one is converted, unless the client lost track of where
+/// the server is in the stream: then the server is stopped. Returns how many files were dealt
+/// with, an error only when the server could not start or nobody listens to the events.
+fn convert_files_with_one_server(
+    mpsc_sender: &Sender<ConvertEvent>,
+    batch: &Batch,
+    files: &[String],
+    first_file_id: usize,
+) -> Result<usize, ConvertError> {
+    let parameters = batch.parameters;
+    let Connection {
+        to_server,
+        mut from_server,
+        mut server,
+    } = batch.transport.connect()?;
+    // Shared between the thread sending the files and the password answers.
+    let to_server = Arc::new(Mutex::new(to_server));
+    let hello = {
+        let mut to_server = lock_stdin(&to_server);
+        protocol::send_hello(&mut *to_server)
+            .and_then(|()| protocol::receive_hello(&mut from_server))
+            .and_then(|()| {
+                ClientMessage::Batch {
+                    passwords: parameters.passwords.clone(),
+                    max_password_attempts: parameters.max_password_attempts,
+                    number_files: u16::try_from(files.len())
+                        .map_err(|_| ProtocolError::InvalidValue("number of files"))?,
+                    options: ConvertOptions {
+                        video_audio: parameters.video_audio,
+                        html_single_page: parameters.html_single_page,
+                        dpi: parameters.dpi,
+                        pages: parameters.pages.clone(),
+                        pixel_format: parameters.pixel_format,
+                    },
+                }
+                .write_to(&mut *to_server)
+            })
+    };
+    if let Err(e) = hello {
+        server.kill();
+        server.wait();
+        return Err(e.into());
+    }
+
+    let (tx, rx) = channel();
+    let to_server_clone = Arc::clone(&to_server);
+    let temporary_directory = batch.temporary_directory.clone();
+    let files_to_send = files.to_vec();
+    let file_passwords = batch.file_passwords.clone();
+    let mpsc_sender_clone = mpsc_sender.clone();
+    // Every file goes through `tx`, with the error that kept it from being sent if any. The
+    // thread stops at the first one, the server is then stopped.
+    thread::spawn(move || {
+        for (file_id, filename) in files_to_send.into_iter().enumerate() {
+            let _ = mpsc_sender_clone.send(ConvertEvent::FileToConvert {
+                file: filename.to_string(),
+            });
+            debug!("Transmitting file {} to server", filename);
+            let temporary_directory_file =
+                format!("{}/{}", &temporary_directory, first_file_id + file_id);
+            let passwords = fs::canonicalize(&filename)
+                .ok()
+                .and_then(|path| file_passwords.get(&path))
+                .map(|password| vec![password.to_string()])
+                .unwrap_or_default();
+            let extension = Path::new(&filename)
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let sent = fs::create_dir_all(&temporary_directory_file)
+                .and_then(|()| fs::read(&filename))
+                .map_err(ConvertError::from)
+                .and_then(|content| {
+                    ClientMessage::File {
+                        content,
+                        extension,
+                        passwords,
+                    }
+                    .write_to(&mut *lock_stdin(&to_server_clone))
+                    .map_err(ConvertError::from)
+                });
+            let stop = sent.is_err();
+            debug!("File {} sent to the server: {:?}", filename, sent);
+            if tx.send((filename, temporary_directory_file, sent)).is_err() || stop {
+                return;
+            }
+        }
+    });
+    let mut number_files = 0;
+    let mut stream_lost = false;
+    let mut result = Ok(());
+    for (filename, temporary_directory_file, sent) in rx {
+        number_files += 1;
+        let file_parameters = fs::canonicalize(&filename)
+            .ok()
+            .and_then(|path| batch.file_image_formats.get(&path))
+            .map(|image_format| ConvertParameters {
+                image_format: *image_format,
+                ..parameters.clone()
+            });
+        let converted = sent.and_then(|()| {
+            convert_one_file(
+                mpsc_sender,
+                &to_server,
+                &mut from_server,
+                &filename,
+                &temporary_directory_file,
+                file_parameters.as_ref().unwrap_or(parameters),
+            )
+        });
+        let _ = fs::remove_dir_all(&temporary_directory_file);
+        let failure = match converted {
+            Ok(()) => archive_source_file(&filename, parameters, &batch.archive_path).err(),
+            Err(e) => {
+                stream_lost = !e.keeps_stream();
+                Some(e)
+            }
+        };
+        if let Some(e) = failure {
+            debug!("Conversion of {} failed: {:?}", filename, e);
+            if let ConvertError::EventsClosed = e {
+                result = Err(e);
+                break;
+            }
+            if let Err(e) = mpsc_sender.send(ConvertEvent::Failure {
+                file: filename.to_string(),
+                message: e.to_string(),
+            }) {
+                result = Err(e.into());
+                break;
+            }
+            if stream_lost {
+                break;
+            }
+        }
+    }
+    // Once stopped, the server can not block the thread sending files anymore.
+    drop(to_server);
+    if stream_lost || result.is_err() {
+        server.kill();
+    }
+    server.wait();
+    result.map(|()| number_files)
+}
+
+// Qrexec target of `file`: the one of its extension if any, `parameters.target` otherwise.
+fn target_of<'a>(parameters: &'a ConvertParameters, file: &str) -> &'a str {
+    Path::new(file)
+        .extension()
+        .and_then(|extension| {
+            parameters
+                .extension_targets
+                .get(&extension.to_string_lossy().to_lowercase())
+        })
+        .unwrap_or(&parameters.target)
+}
+
+/// Convert every file of `parameters` in the qubes they are routed to, through qrexec. Files going
+/// to the same target are converted together, in their original order. When a target fails, the
+/// other ones are still tried and the first error is returned.
 pub fn convert_all_files(
     message_for_ui_emetter: &Sender<ConvertEvent>,
-    mut parameters: ConvertParameters,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut server_process = Command::new(QREXEC_BINARY);
-    server_process
-        .args(&["@dispvm", "qubes.Convert"])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped());
-    if !parameters.stderr {
-        server_process.stderr(Stdio::null());
+    parameters: ConvertParameters,
+) -> Result<(), ConvertError> {
+    let mut targets: Vec<(&str, Vec<String>)> = Vec::new();
+    for file in &parameters.files {
+        let target = target_of(&parameters, file);
+        match targets.iter_mut().find(|(other, _)| *other == target) {
+            Some((_, files)) => files.push(file.to_string()),
+            None => targets.push((target, vec![file.to_string()])),
+        }
     }
-    let mut server_process = server_process
-        .spawn()
-        .expect("Convert server failed to start");
-    parameters.max_pages_converted_in_parallele = if parameters.ocr.is_some() {
+    let mut result = Ok(());
+    for (target, files) in targets {
+        let transport = QrexecTransport {
+            target: target.to_string(),
+            stderr: parameters.stderr,
+        };
+        let target_parameters = ConvertParameters {
+            files,
+            ..parameters.clone()
+        };
+        match convert_all_files_with_transport(
+            message_for_ui_emetter,
+            target_parameters,
+            &transport,
+        ) {
+            Err(ConvertError::EventsClosed) => return Err(ConvertError::EventsClosed),
+            Err(e) if result.is_ok() => result = Err(e),
+            _ => {}
+        }
+    }
+    result
+}
+// Convert the files of `batch` in `share`, one server after the other, each one on a group of
+// files allowed by `Isolation`. An error stops the share, its remaining files are reported as
+// failed.
+fn convert_share(
+    mpsc_sender: &Sender<ConvertEvent>,
+    batch: &Batch,
+    share: Range<usize>,
+) -> Result<(), ConvertError> {
+    let files = &batch.parameters.files[share.clone()];
+    let mut converted = 0;
+    while converted < files.len() {
+        let group_size = batch.parameters.isolation.group_size(&files[converted..]);
+        match convert_files_with_one_server(
+            mpsc_sender,
+            batch,
+            &files[converted..converted + group_size],
+            share.start + converted,
+        ) {
+            Ok(number_files) => converted += number_files,
+            Err(e) => {
+                for file in &files[converted..] {
+                    let _ = mpsc_sender.send(ConvertEvent::Failure {
+                        file: file.to_string(),
+                        message: e.to_string(),
+                    });
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+/// Convert every file of `parameters` with servers reached through `transport`. A file that can
+/// not be converted is reported by a `ConvertEvent::Failure` and the batch goes on: when the
+/// client can not trust the stream of the server anymore, the remaining files are sent to a new
+/// one. An error means that the remaining files were not converted, they are reported as failed
+/// too when possible. Up to `parameters.parallel_servers` servers run at the same time, each one
+/// on its own share of the files.
+pub fn convert_all_files_with_transport(
+    message_for_ui_emetter: &Sender<ConvertEvent>,
+    mut parameters: ConvertParameters,
+    transport: &dyn Transport,
+) -> Result<(), ConvertError> {
+    let max_pages_converted_in_parallele = if parameters.ocr.is_some() {
         parameters.max_pages_converted_in_parallele
     } else {
-        (num_cpus::get()).try_into().unwrap()
+        num_cpus::get().try_into().unwrap_or(u8::MAX)
     };
+    // The parallel servers share the limit, each one runs its own tesseract processes.
+    parameters.max_pages_converted_in_parallele =
+        (max_pages_converted_in_parallele / parameters.parallel_servers.max(1)).max(1);
     debug!("{:?}", parameters);
+    if u16::try_from(parameters.files.len()).is_err() {
+        return Err(limit_exceeded("Too many files in one batch"));
+    }
 
     // We don't use the "/tmp/" directory since it's size is limited and not easily configurable.
     // Example: impossible to convert a GIEC report in the 1go /tmp/ fs.
-    let temporary_directory = format!("/home/user/.temp_qubes_convert_{}", Uuid::new_v4());
+    let temporary_directory = format!(
+        "{}/.temp_qubes_convert_{}",
+        path_to_str(&home_directory()?)?,
+        Uuid::new_v4()
+    );
     fs::create_dir_all(&temporary_directory)?;
     let archive_path = match &parameters.archive {
-        Some(path) => format!("{}/", fs::canonicalize(path).unwrap().to_str().unwrap()),
-        None => default_archive_folder(),
+        Some(path) => format!("{}/", path_to_str(&fs::canonicalize(path)?)?),
+        None => default_archive_folder()?,
     };
     fs::create_dir_all(&archive_path)?;
-    let mut server_process_stdin = server_process.stdin.take().unwrap();
-    let server_process_stdout = server_process.stdout.as_mut().unwrap();
-
-    server_process_stdin.write_all(format!("{}\n", parameters.default_password).as_bytes())?;
-    server_process_stdin.write_all(format!("{}\n", parameters.files.len()).as_bytes())?;
-    let (tx, rx) = channel();
-    let temporary_directory_clone = temporary_directory.clone();
-    let files = parameters.files.clone();
-    let message_for_ui_emetter_clone = message_for_ui_emetter.clone();
-    thread::spawn(move || {
-        for (file_id, filename) in files.into_iter().enumerate() {
-            message_for_ui_emetter_clone
-                .send(ConvertEvent::FileToConvert {
-                    file: filename.to_string(),
+    let batch = Batch {
+        parameters: &parameters,
+        file_passwords: by_canonical_path(&parameters.file_passwords),
+        file_image_formats: by_canonical_path(&parameters.file_image_formats),
+        temporary_directory,
+        archive_path,
+        transport,
+    };
+    // Each server gets a contiguous share of the files, the shares run at the same time.
+    let number_files = parameters.files.len();
+    let share_size = number_files
+        .div_ceil(usize::from(parameters.parallel_servers.max(1)))
+        .max(1);
+    let results: Vec<Result<(), ConvertError>> = thread::scope(|scope| {
+        let shares: Vec<_> = (0..number_files)
+            .step_by(share_size)
+            .map(|first_file| {
+                let mpsc_sender = message_for_ui_emetter.clone();
+                let batch = &batch;
+                scope.spawn(move || {
+                    convert_share(
+                        &mpsc_sender,
+                        batch,
+                        first_file..number_files.min(first_file + share_size),
+                    )
                 })
-                .unwrap();
-            debug!("Transmitting file {} to server", filename);
-            let temporary_directory_file = format!("{}/{}", &temporary_directory_clone, file_id);
-            fs::create_dir_all(&temporary_directory_file).unwrap();
-            let mut buffer = Vec::new();
-            let mut file = File::open(&filename).unwrap();
-            file.read_to_end(&mut buffer).unwrap();
-            server_process_stdin
-                .write_all(format!("{}\n", buffer.len()).as_bytes())
-                .unwrap();
-            server_process_stdin.write_all(&buffer).unwrap();
-            debug!("File {} have been transmitted to the server", filename);
-            tx.send((filename, temporary_directory_file)).unwrap();
-        }
+            })
+            .collect();
+        shares
+            .into_iter()
+            .map(|share| {
+                share.join().unwrap_or_else(|_| {
+                    Err(ConvertError::ServerDied(
+                        "the thread talking to it panicked".to_string(),
+                    ))
+                })
+            })
+            .collect()
     });
-    for file_info in rx {
-        let filename = file_info.0;
-        let temporary_directory_file = file_info.1;
-        if let Err(e) = convert_one_file(
-            message_for_ui_emetter,
-            server_process_stdout,
-            &filename,
-            &temporary_directory_file,
-            &parameters,
-            &archive_path,
-        ) {
-            message_for_ui_emetter.send(ConvertEvent::Failure {
-                file: filename.to_string(),
-                message: format!("{:?}", e),
-            })?;
-        }
-        fs::remove_dir_all(&temporary_directory_file).unwrap();
-    }
-    fs::remove_dir_all(&temporary_directory).unwrap();
-    Ok(())
+    fs::remove_dir_all(&batch.temporary_directory)?;
+    results.into_iter().collect()
 }
@@ -0,0 +1,377 @@
+use crate::icc::srgb_profile;
+use flate2::{write::ZlibEncoder, Compression};
+use std::{
+    fmt,
+    io::{self, Write},
+};
+use uuid::Uuid;
+
+// Identification of PDF/A-2b in XMP, which must not be compressed.
+const PDFA_XMP: &str = "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>
+<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">
+<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">
+<rdf:Description rdf:about=\"\" xmlns:pdfaid=\"http://www.aiim.org/pdfa/ns/id/\">
+<pdfaid:part>2</pdfaid:part>
+<pdfaid:conformance>B</pdfaid:conformance>
+</rdf:Description>
+</rdf:RDF>
+</x:xmpmeta>
+<?xpacket end=\"w\"?>";
+
+/// Number of an indirect object, displayed as a reference to it.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub(crate) struct ObjectId(usize);
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} 0 R", self.0)
+    }
+}
+
+/// Minimal PDF writer, only what is needed to assemble documents from data the client validated
+/// itself. Objects are written as soon as they are added, the cross-reference table at the end.
+pub(crate) struct PdfWriter<W: Write> {
+    writer: W,
+    position: usize,
+    offsets: Vec<Option<usize>>,
+}
+impl<W: Write> PdfWriter<W> {
+    pub(crate) fn new(writer: W) -> io::Result<Self> {
+        let mut pdf = Self {
+            writer,
+            position: 0,
+            offsets: Vec::new(),
+        };
+        // The binary comment tells transfer tools that the file is not text.
+        pdf.write_all(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n")?;
+        Ok(pdf)
+    }
+    /// Get a number for an object written later, when other objects must refer to it first.
+    pub(crate) fn reserve(&mut self) -> ObjectId {
+        self.offsets.push(None);
+        ObjectId(self.offsets.len())
+    }
+    pub(crate) fn write_object(&mut self, id: ObjectId, content: &str) -> io::Result<()> {
+        self.start_object(id)?;
+        self.write_all(content.as_bytes())?;
+        self.write_all(b"\nendobj\n")
+    }
+    pub(crate) fn add_object(&mut self, content: &str) -> io::Result<ObjectId> {
+        let id = self.reserve();
+        self.write_object(id, content)?;
+        Ok(id)
+    }
+    /// `dictionary` holds the entries of the stream dictionary, without `/Length`.
+    pub(crate) fn add_stream(&mut self, dictionary: &str, data: &[u8]) -> io::Result<ObjectId> {
+        let id = self.reserve();
+        self.start_object(id)?;
+        self.write_all(
+            format!("<< {} /Length {} >>\nstream\n", dictionary, data.len()).as_bytes(),
+        )?;
+        self.write_all(data)?;
+        self.write_all(b"\nendstream\nendobj\n")?;
+        Ok(id)
+    }
+    /// Write what PDF/A-2b requires besides the catalog: its XMP identification and the sRGB
+    /// output intent device colors refer to. Returns the entries to add to the catalog.
+    pub(crate) fn add_pdfa_entries(&mut self) -> io::Result<String> {
+        let metadata = self.add_stream("/Type /Metadata /Subtype /XML", PDFA_XMP.as_bytes())?;
+        let profile = self.add_stream("/N 3 /Filter /FlateDecode", &deflate(&srgb_profile())?)?;
+        Ok(format!(
+            "/Metadata {} /OutputIntents [<< /Type /OutputIntent /S /GTS_PDFA1 /OutputConditionIdentifier (sRGB) /Info (sRGB IEC61966-2.1) /DestOutputProfile {} >>]",
+            metadata, profile
+        ))
+    }
+    /// Write the cross-reference table and the trailer, `info` is the document information
+    /// dictionary. Every reserved object must have been written.
+    pub(crate) fn finish(mut self, catalog: ObjectId, info: Option<ObjectId>) -> io::Result<W> {
+        let xref_position = self.position;
+        let mut xref = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            let offset = offset.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "PDF object reserved but not written",
+                )
+            })?;
+            xref.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        // Random, as documents are never updated.
+        let id: String = Uuid::new_v4()
+            .as_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        xref.push_str(&format!(
+            "trailer\n<< /Size {} /Root {}{} /ID [<{}> <{}>] >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len() + 1,
+            catalog,
+            info.map_or_else(String::new, |info| format!(" /Info {}", info)),
+            id,
+            id,
+            xref_position
+        ));
+        self.write_all(xref.as_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+    fn start_object(&mut self, id: ObjectId) -> io::Result<()> {
+        self.offsets[id.0 - 1] = Some(self.position);
+        self.write_all(format!("{} 0 obj\n", id.0).as_bytes())
+    }
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.position += data.len();
+        Ok(())
+    }
+}
+
+/// Compress `data` for the `/FlateDecode` filter.
+pub(crate) fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// PDF literal string, non ASCII bytes are escaped so content streams stay readable.
+pub(crate) fn literal_string(bytes: &[u8]) -> String {
+    let mut string = String::with_capacity(bytes.len() + 2);
+    string.push('(');
+    for byte in bytes {
+        match byte {
+            b'(' | b')' | b'\\' => {
+                string.push('\\');
+                string.push(char::from(*byte));
+            }
+            0x20..=0x7e => string.push(char::from(*byte)),
+            _ => string.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    string.push(')');
+    string
+}
+
+/// PDF text string: a literal string when it is printable ASCII, UTF-16 otherwise.
+pub(crate) fn text_string(text: &str) -> String {
+    if text.bytes().all(|byte| (0x20..=0x7e).contains(&byte)) {
+        return literal_string(text.as_bytes());
+    }
+    let mut string = String::from("<feff");
+    for unit in text.encode_utf16() {
+        string.push_str(&format!("{:04x}", unit));
+    }
+    string.push('>');
+    string
+}
+
+/// Document information dictionary made of text entries.
+pub(crate) fn info_dictionary(entries: &[(&str, String)]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|(key, value)| format!("/{} {}", key, text_string(value)))
+        .collect();
+    format!("<< {} >>", entries.join(" "))
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len())
+        .position(|window| window == pattern)
+}
+
+fn find_last(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len())
+        .rposition(|window| window == pattern)
+}
+
+fn parse_number(data: &[u8]) -> Option<usize> {
+    let digits = data.iter().take_while(|byte| byte.is_ascii_digit()).count();
+    std::str::from_utf8(&data[..digits]).ok()?.parse().ok()
+}
+
+// Bytes of the literal string `data` starts with, and its length in `data`.
+fn parse_literal_string(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut bytes = Vec::new();
+    let mut index = 1;
+    loop {
+        match *data.get(index)? {
+            b')' => return Some((bytes, index + 1)),
+            b'\\' if data.get(index + 1)?.is_ascii_digit() => {
+                let octal = std::str::from_utf8(data.get(index + 1..index + 4)?).ok()?;
+                bytes.push(u8::from_str_radix(octal, 8).ok()?);
+                index += 4;
+            }
+            b'\\' => {
+                bytes.push(*data.get(index + 1)?);
+                index += 2;
+            }
+            byte => {
+                bytes.push(byte);
+                index += 1;
+            }
+        }
+    }
+}
+
+// Bytes of the hexadecimal string `data` starts with, and its length in `data`.
+fn parse_hex_string(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let end = data.iter().position(|&byte| byte == b'>')?;
+    let hex = std::str::from_utf8(&data[1..end]).ok()?;
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some((bytes, end + 1))
+}
+
+// UTF-16 with a byte order mark, or PDFDocEncoding which matches Latin-1 for printable ASCII.
+fn decode_text_string(bytes: &[u8]) -> String {
+    match bytes.strip_prefix(&[0xfe, 0xff]) {
+        Some(utf16) => String::from_utf16_lossy(
+            &utf16
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect::<Vec<u16>>(),
+        ),
+        None => bytes.iter().map(|&byte| char::from(byte)).collect(),
+    }
+}
+
+/// Text entries of the document information dictionary of a PDF written by `PdfWriter`. Files
+/// laid out differently give `None`. Every offset comes from the file, so none is trusted.
+pub(crate) fn read_info(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let trailer = data.get(find_last(data, b"trailer\n")?..)?;
+    let info = parse_number(trailer.get(find(trailer, b"/Info ")?.checked_add(6)?..)?)?;
+    let xref = parse_number(data.get(find_last(data, b"startxref\n")?.checked_add(10)?..)?)?;
+    // Entries are 20 bytes long, the one of object 0 is the only free one.
+    let first_entry = xref
+        .checked_add(find(data.get(xref..)?, b" f \n")?)?
+        .checked_add(4)?
+        .checked_sub(20)?;
+    let entry = first_entry.checked_add(info.checked_mul(20)?)?;
+    let offset = parse_number(data.get(entry..entry.checked_add(10)?)?)?;
+    let object = data.get(offset..)?;
+    let mut rest = object.get(find(object, b"<<")?.checked_add(2)?..)?;
+    let mut entries = Vec::new();
+    loop {
+        rest = &rest[rest
+            .iter()
+            .take_while(|byte| byte.is_ascii_whitespace())
+            .count()..];
+        if rest.starts_with(b">>") {
+            return Some(entries);
+        }
+        let name = rest.strip_prefix(b"/")?;
+        let name_length = name.iter().position(|byte| b" \n()<>/".contains(byte))?;
+        let key = String::from_utf8(name[..name_length].to_vec()).ok()?;
+        rest = &name[name_length..];
+        rest = &rest[rest
+            .iter()
+            .take_while(|byte| byte.is_ascii_whitespace())
+            .count()..];
+        let (bytes, length) = match rest.first()? {
+            b'(' => parse_literal_string(rest)?,
+            b'<' => parse_hex_string(rest)?,
+            _ => return None,
+        };
+        entries.push((key, decode_text_string(&bytes)));
+        rest = &rest[length..];
+    }
+}
+
+// Decompressed content of the streams of `data` written with `deflate`.
+#[cfg(test)]
+pub(crate) fn inflate_streams(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut streams = Vec::new();
+    let mut rest = data;
+    while let Some(start) = find(rest, b">>\nstream\n") {
+        rest = &rest[start + b">>\nstream\n".len()..];
+        let end = match find(rest, b"\nendstream") {
+            Some(end) => end,
+            None => break,
+        };
+        let mut stream = Vec::new();
+        if io::Read::read_to_end(
+            &mut flate2::read::ZlibDecoder::new(&rest[..end]),
+            &mut stream,
+        )
+        .is_ok()
+        {
+            streams.push(stream);
+        }
+        rest = &rest[end..];
+    }
+    streams
+}
+
+#[test]
+fn pdf_writer_test() {
+    let mut pdf = PdfWriter::new(Vec::new()).unwrap();
+    let pages = pdf.reserve();
+    let catalog = pdf
+        .add_object(&format!("<< /Type /Catalog /Pages {} >>", pages))
+        .unwrap();
+    pdf.write_object(pages, "<< /Type /Pages /Kids [] /Count 0 >>")
+        .unwrap();
+    let info = pdf
+        .add_object(&info_dictionary(&[
+            ("SourcePages", "1-2".to_string()),
+            ("SourceFile", "rapport (été).pdf".to_string()),
+            ("Title", "a(b)\\c".to_string()),
+        ]))
+        .unwrap();
+    let pdfa_entries = pdf.add_pdfa_entries().unwrap();
+    assert!(pdfa_entries.starts_with("/Metadata 4 0 R /OutputIntents [<<"));
+    let content = pdf.finish(catalog, Some(info)).unwrap();
+    // Every cross-reference entry must point to the start of its object.
+    let xref = content
+        .windows(5)
+        .position(|window| window == b"xref\n")
+        .unwrap();
+    let table = String::from_utf8(content[xref..].to_vec()).unwrap();
+    for (number, entry) in table.lines().skip(3).take(5).enumerate() {
+        let offset: usize = entry[..10].parse().unwrap();
+        assert!(content[offset..].starts_with(format!("{} 0 obj", number + 1).as_bytes()));
+    }
+    assert!(table.ends_with(&format!("startxref\n{}\n%%EOF\n", xref)));
+    assert!(table.contains("/Root 2 0 R /Info 3 0 R"));
+    assert_eq!(
+        read_info(&content),
+        Some(vec![
+            ("SourcePages".to_string(), "1-2".to_string()),
+            ("SourceFile".to_string(), "rapport (été).pdf".to_string()),
+            ("Title".to_string(), "a(b)\\c".to_string())
+        ])
+    );
+    // Truncated or foreign files have no provenance, whatever their offsets say.
+    for length in 0..content.len() {
+        let info = read_info(&content[..length]);
+        if length < xref {
+            assert_eq!(info, None);
+        }
+    }
+    let foreign = b"%PDF-1.4\nxref\n0 1\n f \ntrailer\n<< /Info 999999999999999999 0 R >>\n\
+                    startxref\n99999999999999999999\n%%EOF\n";
+    assert_eq!(read_info(foreign), None);
+    let foreign = b"%PDF-1.4\n f \ntrailer\n<< /Info 1 0 R >>\nstartxref\n0\n%%EOF\n";
+    assert_eq!(read_info(foreign), None);
+    assert_eq!(text_string("a(b)"), "(a\\(b\\))");
+    assert_eq!(text_string("é"), "<feff00e9>");
+
+    assert!(PdfWriter::new(Vec::new())
+        .map(|mut pdf| {
+            let catalog = pdf.reserve();
+            pdf.finish(catalog, None).is_err()
+        })
+        .unwrap());
+    assert_eq!(literal_string(b"a(b)\\\xe9"), "(a\\(b\\)\\\\\\351)");
+
+    let data = b"q 1 0 0 1 0 0 cm Q\n".repeat(100);
+    let compressed = deflate(&data).unwrap();
+    assert!(compressed.len() < data.len());
+    let mut decompressed = Vec::new();
+    io::Read::read_to_end(
+        &mut flate2::read::ZlibDecoder::new(compressed.as_slice()),
+        &mut decompressed,
+    )
+    .unwrap();
+    assert_eq!(decompressed, data);
+}
@@ -0,0 +1,180 @@
+use crate::{pdf, ConvertError, ConvertParameters};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::{self, File},
+    io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SOURCE_FILE: &str = "SourceFile";
+const SOURCE_SHA256: &str = "SourceSHA256";
+const CONVERSION_DATE: &str = "ConversionDate";
+const CONVERTER_VERSION: &str = "ConverterVersion";
+const CONVERSION_DPI: &str = "ConversionDPI";
+const OCR_LANGUAGE: &str = "OCRLanguage";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// What the client knows by itself of a converted file. It is recorded in the trusted PDFs and
+/// PNGs made from it, in the document information of PDFs and in text chunks of PNGs. Audio,
+/// video and the other image formats do not record it. Nothing in it comes from the server.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Provenance {
+    /// Name of the original file, without its directory.
+    pub source_file: String,
+    pub source_sha256: String,
+    /// UTC, like "2022-01-31T12:00:00Z".
+    pub conversion_date: String,
+    pub converter_version: String,
+    pub dpi: u16,
+    pub ocr_lang: Option<String>,
+}
+impl Provenance {
+    pub(crate) fn new(
+        source_file: &Path,
+        parameters: &ConvertParameters,
+    ) -> Result<Self, ConvertError> {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .as_secs();
+        Ok(Self {
+            source_file: source_file
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            source_sha256: sha256(source_file)?,
+            conversion_date: format_utc(seconds),
+            converter_version: env!("CARGO_PKG_VERSION").to_string(),
+            dpi: parameters.dpi,
+            ocr_lang: parameters.ocr.clone(),
+        })
+    }
+    /// Keys and values as they are recorded.
+    pub(crate) fn entries(&self) -> Vec<(&'static str, String)> {
+        let mut entries = vec![
+            (SOURCE_FILE, self.source_file.clone()),
+            (SOURCE_SHA256, self.source_sha256.clone()),
+            (CONVERSION_DATE, self.conversion_date.clone()),
+            (CONVERTER_VERSION, self.converter_version.clone()),
+            (CONVERSION_DPI, self.dpi.to_string()),
+        ];
+        if let Some(ocr_lang) = &self.ocr_lang {
+            entries.push((OCR_LANGUAGE, ocr_lang.clone()));
+        }
+        entries
+    }
+    fn from_entries(entries: &[(String, String)]) -> Option<Self> {
+        let value = |key: &str| {
+            entries
+                .iter()
+                .find(|(entry_key, _)| entry_key == key)
+                .map(|(_, value)| value.clone())
+        };
+        Some(Self {
+            source_file: value(SOURCE_FILE)?,
+            source_sha256: value(SOURCE_SHA256)?,
+            conversion_date: value(CONVERSION_DATE)?,
+            converter_version: value(CONVERTER_VERSION)?,
+            dpi: value(CONVERSION_DPI)?.parse().ok()?,
+            ocr_lang: value(OCR_LANGUAGE),
+        })
+    }
+}
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in self.entries() {
+            writeln!(f, "{}: {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn sha256(file: &Path) -> Result<String, ConvertError> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(file)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+// UTC date of `seconds` since the Unix epoch. Years are counted from March so leap days end them.
+fn format_utc(seconds: u64) -> String {
+    let days = seconds / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    let time = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+/// Read back the provenance recorded in a trusted PDF or PNG. `None` when the file has none, like
+/// every file of another format.
+pub fn read_provenance(file: &str) -> Result<Option<Provenance>, ConvertError> {
+    let content = fs::read(file)?;
+    let entries = if content.starts_with(b"%PDF-") {
+        pdf::read_info(&content).unwrap_or_default()
+    } else if content.starts_with(PNG_SIGNATURE) {
+        let reader = png::Decoder::new(io::Cursor::new(&content)).read_info()?;
+        let mut entries = Vec::new();
+        for chunk in &reader.info().utf8_text {
+            entries.push((chunk.keyword.clone(), chunk.get_text()?));
+        }
+        entries
+    } else {
+        // Other image formats do not record the provenance.
+        Vec::new()
+    };
+    Ok(Provenance::from_entries(&entries))
+}
+
+#[test]
+fn provenance_test() {
+    assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+    assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(format_utc(1_643_630_400), "2022-01-31T12:00:00Z");
+    assert_eq!(format_utc(4_107_628_799), "2100-03-01T23:59:59Z");
+
+    let file = std::env::temp_dir().join(format!("qubes-converter-sha256-{}", std::process::id()));
+    fs::write(&file, "abc").unwrap();
+    assert_eq!(
+        sha256(&file).unwrap(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(read_provenance(file.to_str().unwrap()).unwrap(), None);
+    fs::remove_file(&file).unwrap();
+
+    let provenance = Provenance {
+        source_file: "report (final).pdf".to_string(),
+        ..crate::test_provenance()
+    };
+    let entries: Vec<(String, String)> = provenance
+        .entries()
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect();
+    assert_eq!(entries.len(), 5);
+    assert_eq!(Provenance::from_entries(&entries), Some(provenance));
+    assert_eq!(Provenance::from_entries(&entries[1..]), None);
+}
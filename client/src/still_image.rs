@@ -0,0 +1,291 @@
+use crate::{
+    image_size_is_valid, limit_exceeded, read_page_header, transport::FromServer, write_png,
+    ConvertError, ConvertEvent, Provenance,
+};
+use image::{
+    codecs::{jpeg::JpegEncoder, tiff::TiffEncoder, webp::WebPEncoder},
+    DynamicImage, GrayImage, ImageEncoder, Luma, Rgb, RgbImage, RgbaImage,
+};
+use qubes_converter_common::protocol::{self, PixelFormat, ProtocolError};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    process::Command,
+    str::FromStr,
+    sync::mpsc::Sender,
+};
+
+const DEFAULT_QUALITY: u8 = 85;
+
+/// Format of trusted still images. Animated images are always animated PNGs, and only PNGs
+/// record the provenance.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum ImageFormat {
+    Png,
+    /// `quality` goes from 1 to 100, like for every lossy format.
+    Jpeg {
+        quality: u8,
+    },
+    Tiff,
+    WebpLossless,
+    /// Encoded by `cwebp`.
+    WebpLossy {
+        quality: u8,
+    },
+    /// Encoded by `avifenc`.
+    Avif {
+        quality: u8,
+    },
+}
+impl ImageFormat {
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg { .. } => "jpeg",
+            Self::Tiff => "tiff",
+            Self::WebpLossless | Self::WebpLossy { .. } => "webp",
+            Self::Avif { .. } => "avif",
+        }
+    }
+}
+impl FromStr for ImageFormat {
+    type Err = String;
+    /// "png", "jpeg", "tiff", "webp", "webp-lossy" or "avif". The quality of lossy formats can
+    /// follow a colon, like "jpeg:75".
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, quality) = match value.split_once(':') {
+            Some((name, quality)) => (
+                name,
+                Some(
+                    quality
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|quality| (1..=100).contains(quality))
+                        .ok_or_else(|| format!("Invalid image quality: {}", quality))?,
+                ),
+            ),
+            None => (value, None),
+        };
+        let lossy_quality = quality.unwrap_or(DEFAULT_QUALITY);
+        match name {
+            "png" | "tiff" | "webp" if quality.is_some() => {
+                Err(format!("{} is lossless, it has no quality", name))
+            }
+            "png" => Ok(Self::Png),
+            "jpeg" => Ok(Self::Jpeg {
+                quality: lossy_quality,
+            }),
+            "tiff" => Ok(Self::Tiff),
+            "webp" => Ok(Self::WebpLossless),
+            "webp-lossy" => Ok(Self::WebpLossy {
+                quality: lossy_quality,
+            }),
+            "avif" => Ok(Self::Avif {
+                quality: lossy_quality,
+            }),
+            _ => Err(format!("Unknown image format: {}", value)),
+        }
+    }
+}
+
+// Black and white pixels become 8 bits gray, the encoders do not know 1 bit images.
+fn to_image(
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+    pixels: Vec<u8>,
+) -> Result<DynamicImage, ConvertError> {
+    let image = match pixel_format {
+        PixelFormat::Rgba => RgbaImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        PixelFormat::Rgb => RgbImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        PixelFormat::Gray => GrayImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        PixelFormat::Bilevel => PixelFormat::Bilevel
+            .payload_size(width, 1)
+            .filter(|row_size| row_size * height as usize == pixels.len())
+            .map(|row_size| {
+                DynamicImage::from(GrayImage::from_fn(width, height, |x, y| {
+                    let byte = pixels[y as usize * row_size + x as usize / 8];
+                    Luma([if byte & (0x80 >> (x % 8)) == 0 {
+                        0
+                    } else {
+                        255
+                    }])
+                }))
+            }),
+    };
+    Ok(image.ok_or(ProtocolError::InvalidValue(
+        "pixels do not match the image size",
+    ))?)
+}
+
+// JPEG has no transparency, transparent pixels are drawn on white.
+fn flatten_on_white(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageRgba8(rgba) => {
+            DynamicImage::from(RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+                let pixel = rgba.get_pixel(x, y);
+                let alpha = u16::from(pixel[3]);
+                #[allow(clippy::cast_possible_truncation)]
+                Rgb([0, 1, 2].map(|channel| {
+                    ((u16::from(pixel[channel]) * alpha + 255 * (255 - alpha)) / 255) as u8
+                }))
+            }))
+        }
+        image => image,
+    }
+}
+
+fn run_encoder(program: &'static str, args: &[&str]) -> Result<(), ConvertError> {
+    let command_output = Command::new(program).args(args).output()?;
+    if !command_output.status.success() {
+        return Err(ConvertError::tool_failed(
+            program,
+            String::from_utf8_lossy(&command_output.stderr),
+        ));
+    }
+    Ok(())
+}
+
+// Formats without a Rust encoder are encoded from a PNG of the validated pixels.
+#[allow(clippy::too_many_arguments)]
+fn write_image(
+    output_file: &str,
+    temporary_file_base: &str,
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+    pixels: Vec<u8>,
+    image_format: ImageFormat,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    let png_file_path = format!("{}.png", temporary_file_base);
+    match image_format {
+        ImageFormat::Png => write_png(
+            output_file,
+            width,
+            height,
+            pixel_format,
+            &pixels,
+            Some(provenance),
+        )?,
+        ImageFormat::Jpeg { quality } => {
+            let image = flatten_on_white(to_image(width, height, pixel_format, pixels)?);
+            let mut writer = BufWriter::new(File::create(output_file)?);
+            JpegEncoder::new_with_quality(&mut writer, quality).encode_image(&image)?;
+            writer.flush()?;
+        }
+        ImageFormat::Tiff => {
+            let image = to_image(width, height, pixel_format, pixels)?;
+            let mut writer = BufWriter::new(File::create(output_file)?);
+            TiffEncoder::new(&mut writer).write_image(
+                image.as_bytes(),
+                width,
+                height,
+                image.color().into(),
+            )?;
+            writer.flush()?;
+        }
+        ImageFormat::WebpLossless => {
+            let image = to_image(width, height, pixel_format, pixels)?;
+            let mut writer = BufWriter::new(File::create(output_file)?);
+            WebPEncoder::new_lossless(&mut writer).write_image(
+                image.as_bytes(),
+                width,
+                height,
+                image.color().into(),
+            )?;
+            writer.flush()?;
+        }
+        ImageFormat::WebpLossy { quality } => {
+            write_png(&png_file_path, width, height, pixel_format, &pixels, None)?;
+            run_encoder(
+                "cwebp",
+                &[
+                    "-quiet",
+                    "-q",
+                    &quality.to_string(),
+                    &png_file_path,
+                    "-o",
+                    output_file,
+                ],
+            )?;
+        }
+        ImageFormat::Avif { quality } => {
+            write_png(&png_file_path, width, height, pixel_format, &pixels, None)?;
+            run_encoder(
+                "avifenc",
+                &["-q", &quality.to_string(), &png_file_path, output_file],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// A still image is one page, re-encoded to `image_format` from the pixels only.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn receive_image(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    temporary_file_base: &str,
+    output_file: &str,
+    pixel_format: PixelFormat,
+    image_format: ImageFormat,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    let (width, height) = read_page_header(process_stdout, pixel_format)?;
+    if !image_size_is_valid(width, height, 4) {
+        return Err(limit_exceeded(
+            "Max image size exceeded: Probably DOS attempt",
+        ));
+    }
+    let pixels = protocol::read_pixels(process_stdout, width, height, pixel_format)?;
+    let result = write_image(
+        output_file,
+        temporary_file_base,
+        width,
+        height,
+        pixel_format,
+        pixels,
+        image_format,
+        provenance,
+    );
+    // An encoder may fail after writing part of the image.
+    if result.is_err() {
+        let _ = fs::remove_file(output_file);
+    }
+    result?;
+    mpsc_sender.send(ConvertEvent::PageConverted {
+        file: source_file.to_string(),
+        page: 0,
+    })?;
+    Ok(())
+}
+
+#[test]
+fn image_format_test() {
+    assert_eq!("png".parse(), Ok(ImageFormat::Png));
+    assert_eq!(
+        "jpeg".parse(),
+        Ok(ImageFormat::Jpeg {
+            quality: DEFAULT_QUALITY
+        })
+    );
+    assert_eq!("avif:40".parse(), Ok(ImageFormat::Avif { quality: 40 }));
+    assert!("webp:40".parse::<ImageFormat>().is_err());
+    assert!("jpeg:0".parse::<ImageFormat>().is_err());
+    assert!("jpeg:101".parse::<ImageFormat>().is_err());
+    assert!("gif".parse::<ImageFormat>().is_err());
+    assert_eq!(ImageFormat::WebpLossy { quality: 1 }.extension(), "webp");
+
+    // Black and white rows are padded to a byte, 1 is white.
+    let image = to_image(9, 2, PixelFormat::Bilevel, vec![0x80, 0x80, 0x00, 0x7f]).unwrap();
+    let gray = image.to_luma8();
+    assert_eq!(gray.get_pixel(0, 0), &Luma([255]));
+    assert_eq!(gray.get_pixel(8, 0), &Luma([255]));
+    assert_eq!(gray.get_pixel(0, 1), &Luma([0]));
+    assert_eq!(gray.get_pixel(1, 1), &Luma([0]));
+    assert!(to_image(9, 2, PixelFormat::Bilevel, vec![0; 3]).is_err());
+    let flattened = flatten_on_white(to_image(1, 1, PixelFormat::Rgba, vec![0, 0, 0, 0]).unwrap());
+    assert_eq!(flattened.to_rgb8().get_pixel(0, 0), &Rgb([255, 255, 255]));
+}
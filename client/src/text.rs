@@ -0,0 +1,335 @@
+use crate::{
+    font::EmbeddedFont,
+    limit_exceeded,
+    pdf::{info_dictionary, literal_string, PdfWriter},
+    transport::FromServer,
+    ConvertError, ConvertEvent, OutputProfile, Provenance,
+};
+use qubes_converter_common::protocol::{self, ProtocolError, ServerMessage};
+use std::{fs::File, io::BufWriter, mem, sync::mpsc::Sender};
+
+// Must match the server.
+const MAX_TEXT_SIZE: usize = 16 * 1024 * 1024;
+
+// A4 in points, typeset in 9 points.
+const PAGE_WIDTH: u32 = 595;
+const PAGE_HEIGHT: u32 = 842;
+const MARGIN: u32 = 36;
+const FONT_SIZE: u32 = 9;
+const LEADING: u32 = 11;
+const LINES_PER_PAGE: usize = 70;
+// In thousandths of the font size, like glyph widths.
+#[allow(clippy::cast_possible_wrap)]
+const LINE_WIDTH: i32 = ((PAGE_WIDTH - 2 * MARGIN) * 1000 / FONT_SIZE) as i32;
+// Every glyph of Courier is 0.6 em wide.
+const COURIER_WIDTH: i32 = 600;
+
+// Code points of the 0x80..=0x9f range of the Windows-1252 encoding, `None` where it is unused.
+const WIN_ANSI_HIGH: [Option<char>; 32] = [
+    Some('\u{20ac}'),
+    None,
+    Some('\u{201a}'),
+    Some('\u{0192}'),
+    Some('\u{201e}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02c6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017d}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201c}'),
+    Some('\u{201d}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02dc}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203a}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017e}'),
+    Some('\u{0178}'),
+];
+
+// Code of `c` in Windows-1252, the only encoding of the standard fonts.
+fn win_ansi_code(c: char) -> Option<u8> {
+    match u32::from(c) {
+        #[allow(clippy::cast_possible_truncation)]
+        code @ (0x20..=0x7e | 0xa0..=0xff) => Some(code as u8),
+        _ => WIN_ANSI_HIGH
+            .iter()
+            .position(|&high| high == Some(c))
+            .map(|index| 0x80 + index as u8),
+    }
+}
+
+// Standard fonts only know Windows-1252, anything else is shown as '?'.
+fn win_ansi_byte(c: char) -> u8 {
+    win_ansi_code(c).unwrap_or(b'?')
+}
+
+// Cut the text in lines of at most `LINE_WIDTH`, long lines continue on the next ones. `encode`
+// gives the code and the width of each character.
+fn layout_lines(text: &str, encode: impl Fn(char) -> (u16, i32)) -> Vec<Vec<u16>> {
+    let mut lines = Vec::new();
+    for line in text.strip_suffix('\n').unwrap_or(text).split('\n') {
+        let mut codes = Vec::new();
+        let mut width = 0;
+        for c in line.chars() {
+            let (code, char_width) = encode(c);
+            if width + char_width > LINE_WIDTH && !codes.is_empty() {
+                lines.push(mem::take(&mut codes));
+                width = 0;
+            }
+            codes.push(code);
+            width += char_width;
+        }
+        lines.push(codes);
+    }
+    lines
+}
+
+// Typeset `text` with an embedded TrueType font, or with Courier when none is installed and the
+// text fits in Windows-1252. PDF/A forbids both missing glyphs and fonts that are not embedded.
+fn write_text_pdf(
+    text: &str,
+    output_file: &str,
+    output_profile: OutputProfile,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    let embedded_font = EmbeddedFont::for_text(text)?;
+    let lines = match &embedded_font {
+        Some(font) => {
+            let missing = font.missing_characters();
+            if !missing.is_empty() && output_profile == OutputProfile::PdfA2b {
+                return Err(ConvertError::tool_failed(
+                    "font",
+                    format!(
+                        "PDF/A needs a glyph for every character, the installed fonts have none for {} characters like {:?}",
+                        missing.len(),
+                        missing[0]
+                    ),
+                ));
+            }
+            if !missing.is_empty() {
+                log::warn!(
+                    "{}: no glyph for {} characters in the installed fonts",
+                    output_file,
+                    missing.len()
+                );
+            }
+            layout_lines(text, |c| font.encode(c).unwrap_or((0, 0)))
+        }
+        None if output_profile == OutputProfile::Standard
+            && text
+                .chars()
+                .all(|c| c == '\n' || win_ansi_code(c).is_some()) =>
+        {
+            layout_lines(text, |c| (u16::from(win_ansi_byte(c)), COURIER_WIDTH))
+        }
+        None => return Err(ConvertError::tool_failed(
+            "font",
+            "no TrueType font for the characters of the text is installed, like DejaVu Sans Mono"
+                .to_string(),
+        )),
+    };
+    let mut pdf = PdfWriter::new(BufWriter::new(File::create(output_file)?))?;
+    let pages = pdf.reserve();
+    let font = match &embedded_font {
+        Some(font) => font.write(&mut pdf)?,
+        None => pdf.add_object(
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Courier /Encoding /WinAnsiEncoding >>",
+        )?,
+    };
+    let mut kids = Vec::new();
+    for page_lines in lines.chunks(LINES_PER_PAGE) {
+        let mut content = format!(
+            "BT\n/F1 {} Tf\n{} TL\n{} {} Td\n",
+            FONT_SIZE,
+            LEADING,
+            MARGIN,
+            PAGE_HEIGHT - MARGIN - FONT_SIZE
+        );
+        for line in page_lines {
+            if embedded_font.is_some() {
+                let cids: String = line.iter().map(|cid| format!("{:04x}", cid)).collect();
+                content.push_str(&format!("<{}>", cids));
+            } else {
+                #[allow(clippy::cast_possible_truncation)]
+                let bytes: Vec<u8> = line.iter().map(|&code| code as u8).collect();
+                content.push_str(&literal_string(&bytes));
+            }
+            content.push_str(" Tj T*\n");
+        }
+        content.push_str("ET");
+        let contents = pdf.add_stream("", content.as_bytes())?;
+        kids.push(pdf.add_object(&format!(
+            "<< /Type /Page /Parent {} /MediaBox [0 0 {} {}] /Resources << /Font << /F1 {} >> >> /Contents {} >>",
+            pages, PAGE_WIDTH, PAGE_HEIGHT, font, contents
+        ))?);
+    }
+    let kids_references: Vec<String> = kids.iter().map(ToString::to_string).collect();
+    pdf.write_object(
+        pages,
+        &format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids_references.join(" "),
+            kids.len()
+        ),
+    )?;
+    let catalog_entries = match output_profile {
+        OutputProfile::Standard => String::new(),
+        OutputProfile::PdfA2b => format!(" {}", pdf.add_pdfa_entries()?),
+    };
+    let catalog = pdf.add_object(&format!(
+        "<< /Type /Catalog /Pages {}{} >>",
+        pages, catalog_entries
+    ))?;
+    let info = pdf.add_object(&info_dictionary(&provenance.entries()))?;
+    pdf.finish(catalog, Some(info))?;
+    Ok(())
+}
+
+// Lines are bounded in each chunk by the protocol, `line_length` carries the length of the last
+// line to the next chunk so that a line split across chunks is bounded too.
+fn check_line_length(line_length: &mut usize, chunk: &str) -> Result<(), ConvertError> {
+    for c in chunk.chars() {
+        if c == '\n' {
+            *line_length = 0;
+        } else {
+            *line_length += 1;
+            if *line_length > protocol::MAX_TEXT_LINE_LENGTH {
+                return Err(ProtocolError::InvalidValue("text line too long").into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Receive the validated text of one document and typeset it into `output_file` on the client.
+/// Pages are the chunks of text sent by the server.
+pub(crate) fn receive_text(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    output_file: &str,
+    number_chunks: u16,
+    output_profile: OutputProfile,
+    provenance: &Provenance,
+) -> Result<(), ConvertError> {
+    let mut text = String::new();
+    let mut line_length = 0;
+    for chunk in 0..number_chunks {
+        let length = match ServerMessage::read_from(process_stdout)? {
+            ServerMessage::TextChunk { length } => length,
+            ServerMessage::Failure(failure) => return Err(failure.into()),
+            _ => return Err(ProtocolError::InvalidValue("expected a text chunk").into()),
+        };
+        let chunk_text = protocol::read_text(process_stdout, length)?;
+        check_line_length(&mut line_length, &chunk_text)?;
+        text.push_str(&chunk_text);
+        if text.len() > MAX_TEXT_SIZE {
+            return Err(limit_exceeded(
+                "Max text size exceeded: Probably DOS attempt",
+            ));
+        }
+        mpsc_sender.send(ConvertEvent::PageConverted {
+            file: source_file.to_string(),
+            page: chunk,
+        })?;
+    }
+    write_text_pdf(&text, output_file, output_profile, provenance)
+}
+
+#[test]
+fn layout_lines_test() {
+    let courier = |c| (u16::from(win_ansi_byte(c)), COURIER_WIDTH);
+    assert_eq!(layout_lines("", courier), vec![Vec::<u16>::new()]);
+    assert_eq!(
+        layout_lines("a\n\nb\n", courier),
+        vec![vec![u16::from(b'a')], vec![], vec![u16::from(b'b')]]
+    );
+    // 96 columns of Courier.
+    let lines = layout_lines(&"x".repeat(96 * 2 + 1), courier);
+    assert_eq!(
+        lines.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![96, 96, 1]
+    );
+    assert_eq!(
+        layout_lines("é€\u{fffd}", courier),
+        vec![vec![0xe9, 0x80, u16::from(b'?')]]
+    );
+    // A glyph wider than a line still gets one.
+    assert_eq!(
+        layout_lines("ab", |c| (u16::from(c as u8), LINE_WIDTH + 1)).len(),
+        2
+    );
+}
+
+#[test]
+fn check_line_length_test() {
+    let half = "x".repeat(protocol::MAX_TEXT_LINE_LENGTH / 2);
+    let mut line_length = 0;
+    check_line_length(&mut line_length, &format!("first\n{}", half)).unwrap();
+    check_line_length(&mut line_length, &half).unwrap();
+    // The same line continues in a third chunk.
+    assert!(check_line_length(&mut line_length, "x").is_err());
+    let mut line_length = 0;
+    check_line_length(&mut line_length, &half).unwrap();
+    check_line_length(&mut line_length, &format!("\n{}", half)).unwrap();
+    assert_eq!(line_length, half.len());
+}
+
+#[test]
+fn write_text_pdf_test() {
+    let output_file = std::env::temp_dir().join(format!(
+        "qubes-converter-text-test-{}.pdf",
+        std::process::id()
+    ));
+    let output_file = output_file.to_str().unwrap();
+    let provenance = crate::test_provenance();
+    write_text_pdf(
+        "Привет, κόσμε\n",
+        output_file,
+        OutputProfile::Standard,
+        &provenance,
+    )
+    .unwrap();
+    let pdf = std::fs::read(output_file).unwrap();
+    let pdf = String::from_utf8_lossy(&pdf);
+    for entry in ["/CIDFontType2", "/Identity-H", "/ToUnicode", "/FontFile2"] {
+        assert!(pdf.contains(entry), "{} missing", entry);
+    }
+    assert!(!pdf.contains("/Courier"));
+    assert!(!pdf.contains("/OutputIntents"));
+
+    write_text_pdf("Привет\n", output_file, OutputProfile::PdfA2b, &provenance).unwrap();
+    let pdf = std::fs::read(output_file).unwrap();
+    let pdf = String::from_utf8_lossy(&pdf);
+    assert!(pdf.contains("/OutputIntents") && pdf.contains("/FontFile2"));
+    // No font has a glyph for a private use character, PDF/A does not allow the missing one.
+    assert!(write_text_pdf(
+        "\u{10fffd}\n",
+        output_file,
+        OutputProfile::PdfA2b,
+        &provenance
+    )
+    .is_err());
+    write_text_pdf(
+        "\u{10fffd}\n",
+        output_file,
+        OutputProfile::Standard,
+        &provenance,
+    )
+    .unwrap();
+    std::fs::remove_file(output_file).unwrap();
+}
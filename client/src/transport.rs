@@ -0,0 +1,285 @@
+use crate::ConvertError;
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+const QREXEC_BINARY: &str = "/usr/bin/qrexec-client-vm";
+const QREXEC_SERVICE: &str = "qubes.Convert";
+/// Qrexec target starting a new disposable qube for each connection.
+pub const DISPOSABLE_TARGET: &str = "@dispvm";
+
+// Longest qube name Qubes accepts.
+const MAX_QUBE_NAME: usize = 31;
+
+/// Check that `target` is a qrexec target a client may ask for: `@dispvm`, `@default`,
+/// `@dispvm:<disposable template>` or the name of a qube.
+pub fn check_target(target: &str) -> Result<(), String> {
+    let qube = match target {
+        "@dispvm" | "@default" => return Ok(()),
+        target => target.strip_prefix("@dispvm:").unwrap_or(target),
+    };
+    if qube.len() <= MAX_QUBE_NAME
+        && qube.starts_with(|c: char| c.is_ascii_alphabetic())
+        && qube
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        Ok(())
+    } else {
+        Err(format!("Invalid qrexec target: {}", target))
+    }
+}
+
+/// What the client reads from the server.
+pub type FromServer = Box<dyn Read + Send>;
+/// What the client writes to the server.
+pub type ToServer = Box<dyn Write + Send>;
+
+/// The server at the other end of a `Connection`.
+pub trait Server: Send {
+    /// Stop the server, even in the middle of a file.
+    fn kill(&mut self);
+    /// Wait until the server ends. It does once killed, or once the client closed its side.
+    fn wait(&mut self);
+}
+
+/// Both directions of the stream between the client and one server.
+pub struct Connection {
+    pub to_server: ToServer,
+    pub from_server: FromServer,
+    pub server: Box<dyn Server>,
+}
+
+/// A way to reach a conversion server. Each connection gets a server of its own, that converts
+/// one batch of files.
+pub trait Transport: Send + Sync {
+    fn connect(&self) -> Result<Connection, ConvertError>;
+}
+
+impl Server for Child {
+    fn kill(&mut self) {
+        let _ = Child::kill(self);
+    }
+    fn wait(&mut self) {
+        let _ = Child::wait(self);
+    }
+}
+
+fn connect_to_process(command: &mut Command, stderr: bool) -> Result<Connection, ConvertError> {
+    command.stdin(Stdio::piped()).stdout(Stdio::piped());
+    if !stderr {
+        command.stderr(Stdio::null());
+    }
+    let mut process = command.spawn().map_err(|e| {
+        ConvertError::ServerDied(format!(
+            "unable to start {:?}: {}",
+            command.get_program(),
+            e
+        ))
+    })?;
+    match (process.stdin.take(), process.stdout.take()) {
+        (Some(stdin), Some(stdout)) => Ok(Connection {
+            to_server: Box::new(stdin),
+            from_server: Box::new(stdout),
+            server: Box::new(process),
+        }),
+        _ => {
+            Server::kill(&mut process);
+            Server::wait(&mut process);
+            Err(ConvertError::ServerDied(
+                "no pipe to the server".to_string(),
+            ))
+        }
+    }
+}
+
+/// The server runs in another qube, through the qubes.Convert qrexec service.
+#[derive(Debug, Clone)]
+pub struct QrexecTransport {
+    /// Qube running the server, `DISPOSABLE_TARGET` for a new disposable qube each time. See
+    /// `check_target`.
+    pub target: String,
+    /// Let the server write on our standard error.
+    pub stderr: bool,
+}
+impl Transport for QrexecTransport {
+    fn connect(&self) -> Result<Connection, ConvertError> {
+        check_target(&self.target).map_err(ConvertError::InvalidTarget)?;
+        connect_to_process(
+            Command::new(QREXEC_BINARY).args([self.target.as_str(), QREXEC_SERVICE]),
+            self.stderr,
+        )
+    }
+}
+
+/// The server binary runs on this machine, as a child process. It is not isolated at all: only
+/// meant for development, and for tests.
+#[derive(Debug, Clone)]
+pub struct LocalTransport {
+    pub server_binary: PathBuf,
+    /// Let the server write on our standard error.
+    pub stderr: bool,
+}
+impl Transport for LocalTransport {
+    fn connect(&self) -> Result<Connection, ConvertError> {
+        connect_to_process(&mut Command::new(&self.server_binary), self.stderr)
+    }
+}
+
+// One direction of an in-memory connection. It is closed when the writer is dropped, both
+// directions fail once the server is killed.
+struct PipeWriter {
+    chunks: Sender<Vec<u8>>,
+    killed: Arc<AtomicBool>,
+}
+struct PipeReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+    killed: Arc<AtomicBool>,
+}
+fn pipe(killed: &Arc<AtomicBool>) -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = channel();
+    (
+        PipeWriter {
+            chunks: sender,
+            killed: Arc::clone(killed),
+        },
+        PipeReader {
+            chunks: receiver,
+            chunk: Vec::new(),
+            position: 0,
+            killed: Arc::clone(killed),
+        },
+    )
+}
+fn check_killed(killed: &AtomicBool) -> io::Result<()> {
+    if killed.load(Ordering::SeqCst) {
+        return Err(io::Error::new(io::ErrorKind::BrokenPipe, "server killed"));
+    }
+    Ok(())
+}
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        check_killed(&self.killed)?;
+        self.chunks
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "reader closed"))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        check_killed(&self.killed)?;
+        while self.position == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                // Writer closed: end of the stream.
+                Err(_) => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.chunk.len() - self.position);
+        buf[..size].copy_from_slice(&self.chunk[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+struct MemoryServer {
+    thread: Option<JoinHandle<()>>,
+    killed: Arc<AtomicBool>,
+}
+impl Server for MemoryServer {
+    fn kill(&mut self) {
+        self.killed.store(true, Ordering::SeqCst);
+    }
+    fn wait(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Function playing the server: it reads what the client sends from its first argument and
+/// answers through the second one.
+pub type ServerFunction = dyn Fn(&mut dyn Read, &mut dyn Write) + Send + Sync;
+
+/// The server is a function running in a thread of this process, like a fake server in tests.
+#[derive(Clone)]
+pub struct MemoryTransport {
+    server: Arc<ServerFunction>,
+}
+impl MemoryTransport {
+    pub fn new(server: impl Fn(&mut dyn Read, &mut dyn Write) + Send + Sync + 'static) -> Self {
+        Self {
+            server: Arc::new(server),
+        }
+    }
+}
+impl Transport for MemoryTransport {
+    fn connect(&self) -> Result<Connection, ConvertError> {
+        let killed = Arc::new(AtomicBool::new(false));
+        let (to_server, mut server_input) = pipe(&killed);
+        let (mut server_output, from_server) = pipe(&killed);
+        let server = Arc::clone(&self.server);
+        let thread = thread::spawn(move || server(&mut server_input, &mut server_output));
+        Ok(Connection {
+            to_server: Box::new(to_server),
+            from_server: Box::new(from_server),
+            server: Box::new(MemoryServer {
+                thread: Some(thread),
+                killed,
+            }),
+        })
+    }
+}
+
+#[test]
+fn check_target_test() {
+    assert!(check_target(DISPOSABLE_TARGET).is_ok());
+    assert!(check_target("@dispvm:office-dvm").is_ok());
+    assert!(check_target("work").is_ok());
+    assert!(check_target("@dispvm:").is_err());
+    assert!(check_target("-h").is_err());
+    assert!(check_target("@adminvm").is_err());
+    assert!(check_target(&"a".repeat(MAX_QUBE_NAME + 1)).is_err());
+}
+
+#[test]
+fn memory_transport_test() {
+    let transport = MemoryTransport::new(|input, output| {
+        let mut request = [0; 4];
+        if input.read_exact(&mut request).is_ok() {
+            request.reverse();
+            let _ = output.write_all(&request);
+        }
+    });
+    let mut connection = transport.connect().unwrap();
+    connection.to_server.write_all(&[1, 2]).unwrap();
+    connection.to_server.write_all(&[3, 4]).unwrap();
+    let mut answer = Vec::new();
+    connection.from_server.read_to_end(&mut answer).unwrap();
+    assert_eq!(answer, [4, 3, 2, 1]);
+    connection.server.wait();
+
+    // A killed server can not be reached anymore.
+    let mut connection = transport.connect().unwrap();
+    connection.server.kill();
+    assert!(connection.to_server.write_all(&[1]).is_err());
+    drop(connection.to_server);
+    connection.server.wait();
+}
@@ -0,0 +1,171 @@
+use crate::{
+    audio, image_size_is_valid, limit_exceeded, transport::FromServer, ConvertError, ConvertEvent,
+};
+use log::debug;
+use qubes_converter_common::protocol::{self, ProtocolError, ServerMessage};
+use std::{
+    fs::{self, File},
+    io::Write,
+    process::{ChildStdin, Command, Stdio},
+    sync::mpsc::Sender,
+};
+
+const MAX_VIDEO_FPS: u8 = 60;
+const MAX_VIDEO_DURATION_SECONDS: u64 = 4 * 3600;
+
+// Copy every frame sent by the server to the encoder, until `VideoEnd`.
+#[allow(clippy::too_many_arguments)]
+fn receive_frames(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    encoder_stdin: &mut ChildStdin,
+    source_file: &str,
+    width: u16,
+    height: u16,
+    frames_per_second: u8,
+    number_seconds: u16,
+) -> Result<(), ConvertError> {
+    let max_frames = MAX_VIDEO_DURATION_SECONDS * u64::from(frames_per_second);
+    let mut number_frames: u64 = 0;
+    loop {
+        match ServerMessage::read_from(process_stdout)? {
+            ServerMessage::VideoFrame => {}
+            ServerMessage::VideoEnd => break,
+            ServerMessage::Failure(failure) => return Err(failure.into()),
+            _ => return Err(ProtocolError::InvalidValue("expected a video frame").into()),
+        }
+        number_frames += 1;
+        if number_frames > max_frames {
+            return Err(limit_exceeded(
+                "Max video duration exceeded: Probably DOS attempt",
+            ));
+        }
+        let frame = protocol::read_rgb(process_stdout, width, height)?;
+        encoder_stdin.write_all(&frame)?;
+        if number_frames.is_multiple_of(u64::from(frames_per_second)) {
+            let second = number_frames / u64::from(frames_per_second) - 1;
+            if let Ok(page) = u16::try_from(second) {
+                if page < number_seconds {
+                    mpsc_sender.send(ConvertEvent::PageConverted {
+                        file: source_file.to_string(),
+                        page,
+                    })?;
+                }
+            }
+        }
+    }
+    debug!("{} frames received", number_frames);
+    Ok(())
+}
+
+fn write_video(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    wav_file_path: &str,
+    log_file_path: &str,
+    output_file: &str,
+    number_seconds: u16,
+) -> Result<(), ConvertError> {
+    let (width, height, frames_per_second, audio_chunks) =
+        match ServerMessage::read_from(process_stdout)? {
+            ServerMessage::VideoFormat {
+                width,
+                height,
+                frames_per_second,
+                audio_chunks,
+            } => (width, height, frames_per_second, audio_chunks),
+            ServerMessage::Failure(failure) => return Err(failure.into()),
+            _ => return Err(ProtocolError::InvalidValue("expected the video format").into()),
+        };
+    debug!(
+        "Video: {}x{} at {} fps, {} audio chunks",
+        width, height, frames_per_second, audio_chunks
+    );
+    if width == 0 || height == 0 || !image_size_is_valid(width.into(), height.into(), 3) {
+        return Err(limit_exceeded(
+            "Max image size exceeded: Probably DOS attempt",
+        ));
+    }
+    if frames_per_second == 0 || frames_per_second > MAX_VIDEO_FPS {
+        return Err(limit_exceeded(
+            "Invalid video frame rate: Probably DOS attempt",
+        ));
+    }
+    let with_audio = audio_chunks > 0;
+    if with_audio {
+        audio::receive_wav(process_stdout, wav_file_path, audio_chunks, |_| Ok(()))?;
+    }
+
+    // ffmpeg only reads trusted data here: raw RGB frames and a WAV file we wrote ourself.
+    let size = format!("{}x{}", width, height);
+    let rate = frames_per_second.to_string();
+    let mut encoder_args = vec![
+        "-nostdin", "-v", "error", "-f", "rawvideo", "-pix_fmt", "rgb24", "-s", &size, "-r", &rate,
+        "-i", "pipe:0",
+    ];
+    if with_audio {
+        encoder_args.extend(&["-i", wav_file_path, "-c:a", "flac"]);
+    }
+    encoder_args.extend(&["-c:v", "ffv1", "-f", "matroska", "-y", output_file]);
+    // Nothing reads the errors while the frames are written, a pipe could fill up and block ffmpeg.
+    let mut encoder = Command::new("ffmpeg")
+        .args(&encoder_args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(File::create(log_file_path)?)
+        .spawn()?;
+    let mut encoder_stdin = encoder.stdin.take().unwrap();
+    let frames_result = receive_frames(
+        mpsc_sender,
+        process_stdout,
+        &mut encoder_stdin,
+        source_file,
+        width,
+        height,
+        frames_per_second,
+        number_seconds,
+    );
+    drop(encoder_stdin);
+    if let Err(e) = frames_result {
+        let _ = encoder.kill();
+        let _ = encoder.wait();
+        return Err(e);
+    }
+    if !encoder.wait()?.success() {
+        return Err(ConvertError::tool_failed(
+            "ffmpeg",
+            fs::read_to_string(log_file_path).unwrap_or_default(),
+        ));
+    }
+    Ok(())
+}
+
+/// Receive the frames, and the audio track if any, of one video and encode `output_file` from
+/// them only. Pages are seconds of video.
+pub(crate) fn receive_video(
+    mpsc_sender: &Sender<ConvertEvent>,
+    process_stdout: &mut FromServer,
+    source_file: &str,
+    temporary_file_base: &str,
+    output_file: &str,
+    number_seconds: u16,
+) -> Result<(), ConvertError> {
+    let wav_file_path = format!("{}.wav", temporary_file_base);
+    let log_file_path = format!("{}.ffmpeg.log", temporary_file_base);
+    let result = write_video(
+        mpsc_sender,
+        process_stdout,
+        source_file,
+        &wav_file_path,
+        &log_file_path,
+        output_file,
+        number_seconds,
+    );
+    let _ = fs::remove_file(&wav_file_path);
+    let _ = fs::remove_file(&log_file_path);
+    if result.is_err() {
+        let _ = fs::remove_file(output_file);
+    }
+    result
+}
@@ -3,17 +3,33 @@
 
 use std::convert::TryFrom;
 
+pub mod protocol;
+
 #[repr(u8)]
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub enum OutputType {
     Image = 1,
     Pdf = 0,
+    Audio = 2,
+    Video = 3,
+    /// A directory tree, every member being converted on its own.
+    Archive = 4,
+    /// The rendered message, followed by its attachments.
+    Email = 5,
+    /// Validated text, typeset into a PDF by the client.
+    Text = 6,
 }
 impl OutputType {
     pub const fn extension(self) -> &'static str {
         match self {
             Self::Pdf => "pdf",
             Self::Image => "png",
+            Self::Audio => "wav",
+            Self::Video => "mkv",
+            // Archives become a directory, named without extension.
+            Self::Archive => "",
+            Self::Email => "pdf",
+            Self::Text => "pdf",
         }
     }
 }
@@ -26,6 +42,21 @@ impl TryFrom<u8> for OutputType {
         if orig == Self::Pdf as u8 {
             return Ok(Self::Pdf);
         }
+        if orig == Self::Audio as u8 {
+            return Ok(Self::Audio);
+        }
+        if orig == Self::Video as u8 {
+            return Ok(Self::Video);
+        }
+        if orig == Self::Archive as u8 {
+            return Ok(Self::Archive);
+        }
+        if orig == Self::Email as u8 {
+            return Ok(Self::Email);
+        }
+        if orig == Self::Text as u8 {
+            return Ok(Self::Text);
+        }
         Err("Impossible value")
     }
 }
@@ -0,0 +1,995 @@
+/*
+ Wire protocol spoken between the client and the server over qrexec.
+
+ Every exchange starts with a handshake: both sides write `MAGIC` followed by
+ their `PROTOCOL_VERSION`, then read the one of the other side. If the values
+ differ, both sides stop right away instead of trying to interpret bytes they
+ do not understand.
+
+ After the handshake, the client sends `ClientMessage` frames and the server
+ answers with `ServerMessage` frames. Each frame starts with a one byte tag.
+ All integers are little endian.
+*/
+use crate::OutputType;
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+};
+
+pub const PROTOCOL_VERSION: u16 = 15;
+const MAGIC: &[u8; 4] = b"QCNV";
+
+// Strings only carry passwords and messages, nothing legitimate comes close to this.
+const MAX_STRING_LENGTH: u32 = 64 * 1024;
+const MAX_PASSWORDS: u16 = 1_024;
+const MAX_PAGE_RANGES: u16 = 1_024;
+
+/// Lowest and highest resolution documents can be rendered at, in dots per inch.
+pub const MIN_RENDER_DPI: u16 = 36;
+pub const MAX_RENDER_DPI: u16 = 600;
+/// Widest `ServerMessage::Page` accepted by the client. The server scales bigger images down.
+pub const MAX_PAGE_WIDTH: u32 = 10_000;
+/// Highest `ServerMessage::Page` accepted by the client. The server scales bigger images down.
+pub const MAX_PAGE_HEIGHT: u32 = 10_000;
+/// Longest line of `OutputType::Text`, in characters. Longer lines are wrapped by the server.
+pub const MAX_TEXT_LINE_LENGTH: usize = 1_000;
+/// Largest `ServerMessage::TextChunk`, in bytes.
+pub const MAX_TEXT_CHUNK_SIZE: u32 = 64 * 1024;
+
+const TAG_BATCH: u8 = 1;
+const TAG_FILE: u8 = 2;
+const TAG_PASSWORD: u8 = 3;
+
+const TAG_FILE_INFO: u8 = 1;
+const TAG_PAGE: u8 = 2;
+const TAG_FAILURE: u8 = 3;
+const TAG_PASSWORD_REQUEST: u8 = 4;
+const TAG_AUDIO_FORMAT: u8 = 5;
+const TAG_AUDIO_CHUNK: u8 = 6;
+const TAG_VIDEO_FORMAT: u8 = 7;
+const TAG_VIDEO_FRAME: u8 = 8;
+const TAG_VIDEO_END: u8 = 9;
+const TAG_ARCHIVE_MEMBER: u8 = 10;
+const TAG_TEXT_CHUNK: u8 = 11;
+const TAG_ANIMATION_FRAME: u8 = 12;
+const TAG_PAGE_SELECTION: u8 = 13;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    BadMagic,
+    VersionMismatch { local: u16, remote: u16 },
+    UnknownTag(u8),
+    InvalidValue(&'static str),
+}
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Protocol IO error: {}", e),
+            Self::BadMagic => write!(
+                f,
+                "The other side does not speak the qubes-converter protocol"
+            ),
+            Self::VersionMismatch { local, remote } => write!(
+                f,
+                "Protocol version mismatch (local: {}, remote: {}). The client and the DispVM template must run the same qubes-converter version",
+                local, remote
+            ),
+            Self::UnknownTag(tag) => write!(f, "Unknown message tag: {}", tag),
+            Self::InvalidValue(message) => write!(f, "Invalid value received: {}", message),
+        }
+    }
+}
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Write our protocol magic and version. Must be the very first bytes sent.
+pub fn send_hello<W: Write>(writer: &mut W) -> Result<(), ProtocolError> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+/// Read the protocol magic and version of the other side, and check that it matches our own.
+pub fn receive_hello<R: Read>(reader: &mut R) -> Result<(), ProtocolError> {
+    let mut magic = [0_u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+    let remote = read_u16(reader)?;
+    if remote != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote,
+        });
+    }
+    Ok(())
+}
+
+/// Why the server gave up on one file.
+#[repr(u8)]
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum FailureReason {
+    UnsupportedType = 1,
+    WrongPassword = 2,
+    RendererCrash = 3,
+    LimitExceeded = 4,
+    EmptySelection = 5,
+}
+impl FailureReason {
+    pub const fn description(self) -> &'static str {
+        match self {
+            Self::UnsupportedType => "Unsupported file type",
+            Self::WrongPassword => "Wrong password",
+            Self::RendererCrash => "Renderer crashed",
+            Self::LimitExceeded => "Limit exceeded",
+            Self::EmptySelection => "No page selected",
+        }
+    }
+}
+impl TryFrom<u8> for FailureReason {
+    type Error = &'static str;
+    fn try_from(orig: u8) -> Result<Self, Self::Error> {
+        match orig {
+            1 => Ok(Self::UnsupportedType),
+            2 => Ok(Self::WrongPassword),
+            3 => Ok(Self::RendererCrash),
+            4 => Ok(Self::LimitExceeded),
+            5 => Ok(Self::EmptySelection),
+            _ => Err("Impossible value"),
+        }
+    }
+}
+
+/// Per-file failure reported by the server. The server moves on to the next file after sending
+/// it, so the receiver must stop expecting pages for the current file.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ConversionFailure {
+    pub reason: FailureReason,
+    pub message: String,
+}
+impl ConversionFailure {
+    pub fn new(reason: FailureReason, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+        }
+    }
+}
+impl fmt::Display for ConversionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.reason.description(), self.message)
+    }
+}
+impl std::error::Error for ConversionFailure {}
+
+/// Layout of the pixels following a `ServerMessage::Page`. Rows are stored top to bottom with no
+/// padding, except for `Bilevel` where each row starts on a byte: 8 pixels per byte, the most
+/// significant bit first, 1 is white.
+#[repr(u8)]
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum PixelFormat {
+    Rgba = 0,
+    Rgb = 1,
+    Gray = 2,
+    Bilevel = 3,
+}
+impl PixelFormat {
+    /// Size of the pixels of a `width` x `height` page, `None` when it does not fit in memory.
+    pub fn payload_size(self, width: u32, height: u32) -> Option<usize> {
+        let row = match self {
+            Self::Rgba => u64::from(width) * 4,
+            Self::Rgb => u64::from(width) * 3,
+            Self::Gray => u64::from(width),
+            Self::Bilevel => u64::from(width).div_ceil(8),
+        };
+        usize::try_from(row.checked_mul(u64::from(height))?).ok()
+    }
+}
+impl TryFrom<u8> for PixelFormat {
+    type Error = &'static str;
+    fn try_from(orig: u8) -> Result<Self, Self::Error> {
+        match orig {
+            0 => Ok(Self::Rgba),
+            1 => Ok(Self::Rgb),
+            2 => Ok(Self::Gray),
+            3 => Ok(Self::Bilevel),
+            _ => Err("pixel format"),
+        }
+    }
+}
+impl FromStr for PixelFormat {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "rgba" => Ok(Self::Rgba),
+            "rgb" => Ok(Self::Rgb),
+            "gray" => Ok(Self::Gray),
+            "bilevel" => Ok(Self::Bilevel),
+            _ => Err(format!("Unknown pixel format: {}", value)),
+        }
+    }
+}
+
+/// Inclusive range of pages, numbered from 1. `last` is `u16::MAX` for "until the end".
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PageRange {
+    pub first: u16,
+    pub last: u16,
+}
+impl PageRange {
+    pub const fn contains(self, page: u16) -> bool {
+        self.first <= page && page <= self.last
+    }
+}
+
+/// Parse a selection like "1-10,45,100-".
+pub fn parse_page_ranges(value: &str) -> Result<Vec<PageRange>, String> {
+    let parse_page = |page: &str| match page.trim().parse::<u16>() {
+        Ok(page) if page > 0 => Ok(page),
+        _ => Err(format!("Invalid page number: {:?}", page)),
+    };
+    let mut ranges = Vec::new();
+    for range in value.split(',') {
+        let range = match range.split_once('-') {
+            None => {
+                let page = parse_page(range)?;
+                PageRange {
+                    first: page,
+                    last: page,
+                }
+            }
+            Some((first, last)) => PageRange {
+                first: parse_page(first)?,
+                last: if last.trim().is_empty() {
+                    u16::MAX
+                } else {
+                    parse_page(last)?
+                },
+            },
+        };
+        if range.first > range.last {
+            return Err(format!("Invalid page range: {}", value));
+        }
+        ranges.push(range);
+    }
+    if ranges.len() > usize::from(MAX_PAGE_RANGES) {
+        return Err(format!("At most {} page ranges", MAX_PAGE_RANGES));
+    }
+    Ok(ranges)
+}
+
+/// Pages, among the `number_pages` of a document, selected by `ranges`. Every page when there is
+/// no range.
+pub fn selected_pages(ranges: &[PageRange], number_pages: u16) -> Vec<u16> {
+    (1..=number_pages)
+        .filter(|page| ranges.is_empty() || ranges.iter().any(|range| range.contains(*page)))
+        .collect()
+}
+
+/// Write sorted pages the way `parse_page_ranges` reads them, like "1-10,45".
+pub fn format_pages(pages: &[u16]) -> String {
+    let mut ranges: Vec<(u16, u16)> = Vec::new();
+    for &page in pages {
+        match ranges.last_mut() {
+            Some((_, last)) if u32::from(*last) + 1 == u32::from(page) => *last = page,
+            _ => ranges.push((page, page)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{}-{}", first, last)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Conversion settings chosen by the client, they apply to every file of the batch.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ConvertOptions {
+    /// Decode and send the audio track of videos.
+    pub video_audio: bool,
+    /// Render HTML and Markdown documents as one long image instead of pages.
+    pub html_single_page: bool,
+    /// Resolution of the pages rendered from documents, in dots per inch.
+    pub dpi: u16,
+    /// Pages of paginated documents to convert, all of them when empty.
+    pub pages: Vec<PageRange>,
+    /// Format of every `ServerMessage::Page`.
+    pub pixel_format: PixelFormat,
+}
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            video_audio: true,
+            html_single_page: false,
+            dpi: 150,
+            pages: Vec::new(),
+            pixel_format: PixelFormat::Rgba,
+        }
+    }
+}
+impl ConvertOptions {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        writer.write_all(&[u8::from(self.video_audio), u8::from(self.html_single_page)])?;
+        writer.write_all(&self.dpi.to_le_bytes())?;
+        let number_ranges = u16::try_from(self.pages.len())
+            .map_err(|_| ProtocolError::InvalidValue("page ranges"))?;
+        writer.write_all(&number_ranges.to_le_bytes())?;
+        for range in &self.pages {
+            writer.write_all(&range.first.to_le_bytes())?;
+            writer.write_all(&range.last.to_le_bytes())?;
+        }
+        writer.write_all(&[self.pixel_format as u8])?;
+        Ok(())
+    }
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        let video_audio = read_bool(reader)?;
+        let html_single_page = read_bool(reader)?;
+        let dpi = read_u16(reader)?;
+        if !(MIN_RENDER_DPI..=MAX_RENDER_DPI).contains(&dpi) {
+            return Err(ProtocolError::InvalidValue("resolution"));
+        }
+        let number_ranges = read_u16(reader)?;
+        if number_ranges > MAX_PAGE_RANGES {
+            return Err(ProtocolError::InvalidValue("too many page ranges"));
+        }
+        let mut pages = Vec::new();
+        for _ in 0..number_ranges {
+            let range = PageRange {
+                first: read_u16(reader)?,
+                last: read_u16(reader)?,
+            };
+            if range.first == 0 || range.first > range.last {
+                return Err(ProtocolError::InvalidValue("page range"));
+            }
+            pages.push(range);
+        }
+        let pixel_format =
+            PixelFormat::try_from(read_u8(reader)?).map_err(ProtocolError::InvalidValue)?;
+        Ok(Self {
+            video_audio,
+            html_single_page,
+            dpi,
+            pages,
+            pixel_format,
+        })
+    }
+}
+
+/// Frames sent by the client to the server.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// First frame after the handshake, announce how many files will follow. `passwords` are
+    /// tried in order on every protected file before asking the client.
+    Batch {
+        passwords: Vec<String>,
+        max_password_attempts: u8,
+        number_files: u16,
+        options: ConvertOptions,
+    },
+    /// One untrusted file to convert. Its own `passwords` are tried before the batch ones.
+    /// `extension` only tells apart formats without signature, like Markdown.
+    File {
+        content: Vec<u8>,
+        extension: String,
+        passwords: Vec<String>,
+    },
+    /// Answer to a `ServerMessage::PasswordRequest`. `None` gives up on the file.
+    Password { password: Option<String> },
+}
+impl ClientMessage {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        match self {
+            Self::Batch {
+                passwords,
+                max_password_attempts,
+                number_files,
+                options,
+            } => {
+                writer.write_all(&[TAG_BATCH])?;
+                write_strings(writer, passwords)?;
+                writer.write_all(&[*max_password_attempts])?;
+                writer.write_all(&number_files.to_le_bytes())?;
+                options.write_to(writer)?;
+            }
+            Self::File {
+                content,
+                extension,
+                passwords,
+            } => {
+                writer.write_all(&[TAG_FILE])?;
+                write_string(writer, extension)?;
+                write_strings(writer, passwords)?;
+                writer.write_all(&(content.len() as u64).to_le_bytes())?;
+                writer.write_all(content)?;
+            }
+            Self::Password { password } => match password {
+                Some(password) => {
+                    writer.write_all(&[TAG_PASSWORD, 1])?;
+                    write_string(writer, password)?;
+                }
+                None => writer.write_all(&[TAG_PASSWORD, 0])?,
+            },
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        match read_u8(reader)? {
+            TAG_BATCH => Ok(Self::Batch {
+                passwords: read_strings(reader)?,
+                max_password_attempts: read_u8(reader)?,
+                number_files: read_u16(reader)?,
+                options: ConvertOptions::read_from(reader)?,
+            }),
+            TAG_FILE => {
+                let extension = read_string(reader)?;
+                let passwords = read_strings(reader)?;
+                let size = usize::try_from(read_u64(reader)?)
+                    .map_err(|_| ProtocolError::InvalidValue("file size"))?;
+                let mut content = vec![0; size];
+                reader.read_exact(&mut content)?;
+                Ok(Self::File {
+                    content,
+                    extension,
+                    passwords,
+                })
+            }
+            TAG_PASSWORD => match read_u8(reader)? {
+                0 => Ok(Self::Password { password: None }),
+                1 => Ok(Self::Password {
+                    password: Some(read_string(reader)?),
+                }),
+                _ => Err(ProtocolError::InvalidValue("password flag")),
+            },
+            tag => Err(ProtocolError::UnknownTag(tag)),
+        }
+    }
+}
+
+/// Frames sent by the server to the client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServerMessage {
+    /// Sent once per file, before any of its pages.
+    FileInfo {
+        number_pages: u16,
+        output_type: OutputType,
+    },
+    /// Header of one page. It is followed by the pixels, in the `PixelFormat` chosen by the
+    /// client, that must be read with `read_pixels` once the receiver validated the header.
+    Page {
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+    },
+    /// The current file can not be converted. Can replace `FileInfo` or any `Page`.
+    Failure(ConversionFailure),
+    /// The file number `file` of the batch is protected by a password. Sent before `FileInfo`,
+    /// the server then waits for a `ClientMessage::Password`.
+    PasswordRequest { file: u16, attempt: u8 },
+    /// Sent right after `FileInfo` for `OutputType::Audio`. Samples are signed 16 bits little
+    /// endian, interleaved.
+    AudioFormat { sample_rate: u32, channels: u8 },
+    /// Audio equivalent of a page, `number_pages` of them follow the `AudioFormat`. It is
+    /// followed by `number_frames * channels * 2` bytes, that must be read with `read_samples`
+    /// once the receiver validated the size.
+    AudioChunk { number_frames: u32 },
+    /// Sent right after `FileInfo` for `OutputType::Video`, where `number_pages` is the
+    /// approximate duration in seconds. When `audio_chunks` is not 0, an `AudioFormat` and
+    /// `audio_chunks` `AudioChunk` follow, then the frames.
+    VideoFormat {
+        width: u16,
+        height: u16,
+        frames_per_second: u8,
+        audio_chunks: u16,
+    },
+    /// One frame of the video. It is followed by `width * height * 3` bytes of RGB data, that
+    /// must be read with `read_rgb` once the receiver validated the dimensions.
+    VideoFrame,
+    /// No more frames for the current video.
+    VideoEnd,
+    /// For `OutputType::Archive`, `number_pages` members follow the `FileInfo`. Each one is
+    /// this frame, then the conversion of the member from its own `FileInfo` on, exactly like a
+    /// file of the batch. `path` comes from the untrusted archive.
+    ///
+    /// For `OutputType::Email`, the conversion of the rendered message follows the `FileInfo`
+    /// directly, then `number_pages - 1` attachments are sent as members.
+    ArchiveMember { path: String },
+    /// For `OutputType::Text`, `number_pages` of them follow the `FileInfo`. It is followed by
+    /// `length` bytes of text, that must be read with `read_text`.
+    TextChunk { length: u32 },
+    /// For `OutputType::Pdf`, sent right after the `FileInfo`: number of pages of the whole
+    /// document. `number_pages` of the `FileInfo` only counts the pages selected by
+    /// `ConvertOptions::pages`, the selected pages follow in order.
+    PageSelection { total_pages: u16 },
+    /// For `OutputType::Image` with more than one page, sent before the `Page` of each frame.
+    /// Every frame is a full image, shown for `delay_milliseconds`.
+    AnimationFrame { delay_milliseconds: u16 },
+}
+impl ServerMessage {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), ProtocolError> {
+        match self {
+            Self::FileInfo {
+                number_pages,
+                output_type,
+            } => {
+                writer.write_all(&[TAG_FILE_INFO])?;
+                writer.write_all(&number_pages.to_le_bytes())?;
+                writer.write_all(&[*output_type as u8])?;
+            }
+            Self::Page {
+                width,
+                height,
+                format,
+            } => {
+                writer.write_all(&[TAG_PAGE])?;
+                writer.write_all(&width.to_le_bytes())?;
+                writer.write_all(&height.to_le_bytes())?;
+                writer.write_all(&[*format as u8])?;
+            }
+            Self::Failure(failure) => {
+                writer.write_all(&[TAG_FAILURE, failure.reason as u8])?;
+                write_string(writer, &failure.message)?;
+            }
+            Self::PasswordRequest { file, attempt } => {
+                writer.write_all(&[TAG_PASSWORD_REQUEST])?;
+                writer.write_all(&file.to_le_bytes())?;
+                writer.write_all(&[*attempt])?;
+            }
+            Self::AudioFormat {
+                sample_rate,
+                channels,
+            } => {
+                writer.write_all(&[TAG_AUDIO_FORMAT])?;
+                writer.write_all(&sample_rate.to_le_bytes())?;
+                writer.write_all(&[*channels])?;
+            }
+            Self::AudioChunk { number_frames } => {
+                writer.write_all(&[TAG_AUDIO_CHUNK])?;
+                writer.write_all(&number_frames.to_le_bytes())?;
+            }
+            Self::VideoFormat {
+                width,
+                height,
+                frames_per_second,
+                audio_chunks,
+            } => {
+                writer.write_all(&[TAG_VIDEO_FORMAT])?;
+                writer.write_all(&width.to_le_bytes())?;
+                writer.write_all(&height.to_le_bytes())?;
+                writer.write_all(&[*frames_per_second])?;
+                writer.write_all(&audio_chunks.to_le_bytes())?;
+            }
+            Self::VideoFrame => writer.write_all(&[TAG_VIDEO_FRAME])?,
+            Self::VideoEnd => writer.write_all(&[TAG_VIDEO_END])?,
+            Self::ArchiveMember { path } => {
+                writer.write_all(&[TAG_ARCHIVE_MEMBER])?;
+                write_string(writer, path)?;
+            }
+            Self::PageSelection { total_pages } => {
+                writer.write_all(&[TAG_PAGE_SELECTION])?;
+                writer.write_all(&total_pages.to_le_bytes())?;
+            }
+            Self::AnimationFrame { delay_milliseconds } => {
+                writer.write_all(&[TAG_ANIMATION_FRAME])?;
+                writer.write_all(&delay_milliseconds.to_le_bytes())?;
+            }
+            Self::TextChunk { length } => {
+                writer.write_all(&[TAG_TEXT_CHUNK])?;
+                writer.write_all(&length.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        match read_u8(reader)? {
+            TAG_FILE_INFO => {
+                let number_pages = read_u16(reader)?;
+                let output_type =
+                    OutputType::try_from(read_u8(reader)?).map_err(ProtocolError::InvalidValue)?;
+                Ok(Self::FileInfo {
+                    number_pages,
+                    output_type,
+                })
+            }
+            TAG_PAGE => Ok(Self::Page {
+                width: read_u32(reader)?,
+                height: read_u32(reader)?,
+                format: PixelFormat::try_from(read_u8(reader)?)
+                    .map_err(ProtocolError::InvalidValue)?,
+            }),
+            TAG_FAILURE => {
+                let reason = FailureReason::try_from(read_u8(reader)?)
+                    .map_err(ProtocolError::InvalidValue)?;
+                Ok(Self::Failure(ConversionFailure {
+                    reason,
+                    message: read_string(reader)?,
+                }))
+            }
+            TAG_PASSWORD_REQUEST => Ok(Self::PasswordRequest {
+                file: read_u16(reader)?,
+                attempt: read_u8(reader)?,
+            }),
+            TAG_AUDIO_FORMAT => Ok(Self::AudioFormat {
+                sample_rate: read_u32(reader)?,
+                channels: read_u8(reader)?,
+            }),
+            TAG_AUDIO_CHUNK => Ok(Self::AudioChunk {
+                number_frames: read_u32(reader)?,
+            }),
+            TAG_VIDEO_FORMAT => Ok(Self::VideoFormat {
+                width: read_u16(reader)?,
+                height: read_u16(reader)?,
+                frames_per_second: read_u8(reader)?,
+                audio_chunks: read_u16(reader)?,
+            }),
+            TAG_VIDEO_FRAME => Ok(Self::VideoFrame),
+            TAG_VIDEO_END => Ok(Self::VideoEnd),
+            TAG_ARCHIVE_MEMBER => Ok(Self::ArchiveMember {
+                path: read_string(reader)?,
+            }),
+            TAG_TEXT_CHUNK => Ok(Self::TextChunk {
+                length: read_u32(reader)?,
+            }),
+            TAG_PAGE_SELECTION => Ok(Self::PageSelection {
+                total_pages: read_u16(reader)?,
+            }),
+            TAG_ANIMATION_FRAME => Ok(Self::AnimationFrame {
+                delay_milliseconds: read_u16(reader)?,
+            }),
+            tag => Err(ProtocolError::UnknownTag(tag)),
+        }
+    }
+}
+
+/// Read the pixels following a `ServerMessage::Page`.
+pub fn read_pixels<R: Read>(
+    reader: &mut R,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+) -> Result<Vec<u8>, ProtocolError> {
+    let size = format
+        .payload_size(width, height)
+        .ok_or(ProtocolError::InvalidValue("page size"))?;
+    let mut buffer = vec![0; size];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Read the RGB payload following a `ServerMessage::VideoFrame`.
+pub fn read_rgb<R: Read>(
+    reader: &mut R,
+    width: u16,
+    height: u16,
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut buffer = vec![0; usize::from(width) * usize::from(height) * 3];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Characters allowed in `OutputType::Text`: anything printable, and line feeds. Bidirectional
+/// controls are refused too, they can make the text read differently than it is stored.
+pub fn is_allowed_text_char(c: char) -> bool {
+    let bidirectional_control = matches!(
+        c,
+        '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+    );
+    c == '\n' || !(c.is_control() || bidirectional_control)
+}
+
+/// Read and validate the text following a `ServerMessage::TextChunk`.
+pub fn read_text<R: Read>(reader: &mut R, length: u32) -> Result<String, ProtocolError> {
+    if length > MAX_TEXT_CHUNK_SIZE {
+        return Err(ProtocolError::InvalidValue("text chunk too long"));
+    }
+    let mut buffer = vec![0; length as usize];
+    reader.read_exact(&mut buffer)?;
+    let text =
+        String::from_utf8(buffer).map_err(|_| ProtocolError::InvalidValue("text is not utf8"))?;
+    if !text.chars().all(is_allowed_text_char) {
+        return Err(ProtocolError::InvalidValue("forbidden character in text"));
+    }
+    if text
+        .split('\n')
+        .any(|line| line.chars().count() > MAX_TEXT_LINE_LENGTH)
+    {
+        return Err(ProtocolError::InvalidValue("text line too long"));
+    }
+    Ok(text)
+}
+
+/// Read the samples following a `ServerMessage::AudioChunk`.
+pub fn read_samples<R: Read>(
+    reader: &mut R,
+    number_frames: u32,
+    channels: u8,
+) -> Result<Vec<u8>, ProtocolError> {
+    let size = usize::try_from(number_frames)
+        .map_err(|_| ProtocolError::InvalidValue("number of frames"))?
+        * usize::from(channels)
+        * 2;
+    let mut buffer = vec![0; size];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, ProtocolError> {
+    let mut buffer = [0_u8; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+fn read_bool<R: Read>(reader: &mut R) -> Result<bool, ProtocolError> {
+    match read_u8(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(ProtocolError::InvalidValue("boolean")),
+    }
+}
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, ProtocolError> {
+    let mut buffer = [0_u8; 2];
+    reader.read_exact(&mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
+}
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, ProtocolError> {
+    let mut buffer = [0_u8; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, ProtocolError> {
+    let mut buffer = [0_u8; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+fn write_string<W: Write>(writer: &mut W, value: &str) -> Result<(), ProtocolError> {
+    let length = u32::try_from(value.len())
+        .ok()
+        .filter(|length| *length <= MAX_STRING_LENGTH)
+        .ok_or(ProtocolError::InvalidValue("string too long"))?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(value.as_bytes())?;
+    Ok(())
+}
+fn write_strings<W: Write>(writer: &mut W, values: &[String]) -> Result<(), ProtocolError> {
+    let length = u16::try_from(values.len())
+        .ok()
+        .filter(|length| *length <= MAX_PASSWORDS)
+        .ok_or(ProtocolError::InvalidValue("too many strings"))?;
+    writer.write_all(&length.to_le_bytes())?;
+    for value in values {
+        write_string(writer, value)?;
+    }
+    Ok(())
+}
+fn read_strings<R: Read>(reader: &mut R) -> Result<Vec<String>, ProtocolError> {
+    let length = read_u16(reader)?;
+    if length > MAX_PASSWORDS {
+        return Err(ProtocolError::InvalidValue("too many strings"));
+    }
+    (0..length).map(|_| read_string(reader)).collect()
+}
+fn read_string<R: Read>(reader: &mut R) -> Result<String, ProtocolError> {
+    let length = read_u32(reader)?;
+    if length > MAX_STRING_LENGTH {
+        return Err(ProtocolError::InvalidValue("string too long"));
+    }
+    let mut buffer = vec![0; length as usize];
+    reader.read_exact(&mut buffer)?;
+    String::from_utf8(buffer).map_err(|_| ProtocolError::InvalidValue("string is not utf8"))
+}
+
+#[test]
+fn handshake_test() {
+    let mut buffer = Vec::new();
+    send_hello(&mut buffer).unwrap();
+    receive_hello(&mut buffer.as_slice()).unwrap();
+
+    let mut buffer = MAGIC.to_vec();
+    buffer.extend_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    match receive_hello(&mut buffer.as_slice()) {
+        Err(ProtocolError::VersionMismatch { local, remote }) => {
+            assert_eq!(local, PROTOCOL_VERSION);
+            assert_eq!(remote, PROTOCOL_VERSION + 1);
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    let buffer = b"GARBAGE".to_vec();
+    assert!(matches!(
+        receive_hello(&mut buffer.as_slice()),
+        Err(ProtocolError::BadMagic)
+    ));
+}
+
+#[test]
+fn messages_round_trip_test() {
+    let client_messages = vec![
+        ClientMessage::Batch {
+            passwords: vec!["toor".to_string(), "hunter2".to_string()],
+            max_password_attempts: 3,
+            number_files: 3,
+            options: ConvertOptions {
+                video_audio: false,
+                html_single_page: true,
+                dpi: 300,
+                pages: vec![
+                    PageRange { first: 1, last: 10 },
+                    PageRange {
+                        first: 100,
+                        last: u16::MAX,
+                    },
+                ],
+                pixel_format: PixelFormat::Gray,
+            },
+        },
+        ClientMessage::File {
+            content: vec![1, 2, 3, 4],
+            extension: "md".to_string(),
+            passwords: vec!["toor".to_string()],
+        },
+        ClientMessage::Password {
+            password: Some("toor".to_string()),
+        },
+        ClientMessage::Password { password: None },
+    ];
+    let mut buffer = Vec::new();
+    for message in &client_messages {
+        message.write_to(&mut buffer).unwrap();
+    }
+    let mut reader = buffer.as_slice();
+    for message in client_messages {
+        assert_eq!(message, ClientMessage::read_from(&mut reader).unwrap());
+    }
+    let mut buffer = Vec::new();
+    ConvertOptions {
+        dpi: MAX_RENDER_DPI + 1,
+        ..ConvertOptions::default()
+    }
+    .write_to(&mut buffer)
+    .unwrap();
+    assert!(matches!(
+        ConvertOptions::read_from(&mut buffer.as_slice()),
+        Err(ProtocolError::InvalidValue(_))
+    ));
+
+    let server_messages = vec![
+        ServerMessage::FileInfo {
+            number_pages: 12,
+            output_type: OutputType::Pdf,
+        },
+        ServerMessage::Page {
+            width: 640,
+            height: 480,
+            format: PixelFormat::Rgba,
+        },
+        ServerMessage::Page {
+            width: 70_000,
+            height: 1,
+            format: PixelFormat::Bilevel,
+        },
+        ServerMessage::Failure(ConversionFailure::new(
+            FailureReason::WrongPassword,
+            "pdftk was unable to open the file",
+        )),
+        ServerMessage::PasswordRequest {
+            file: 2,
+            attempt: 1,
+        },
+        ServerMessage::AudioFormat {
+            sample_rate: 44_100,
+            channels: 2,
+        },
+        ServerMessage::AudioChunk {
+            number_frames: 441_000,
+        },
+        ServerMessage::VideoFormat {
+            width: 1280,
+            height: 720,
+            frames_per_second: 25,
+            audio_chunks: 3,
+        },
+        ServerMessage::VideoFrame,
+        ServerMessage::VideoEnd,
+        ServerMessage::ArchiveMember {
+            path: "documents/report.docx".to_string(),
+        },
+        ServerMessage::TextChunk { length: 1_024 },
+        ServerMessage::PageSelection { total_pages: 3_900 },
+        ServerMessage::AnimationFrame {
+            delay_milliseconds: 100,
+        },
+    ];
+    let mut buffer = Vec::new();
+    for message in &server_messages {
+        message.write_to(&mut buffer).unwrap();
+    }
+    let mut reader = buffer.as_slice();
+    for message in server_messages {
+        assert_eq!(message, ServerMessage::read_from(&mut reader).unwrap());
+    }
+    assert!(matches!(
+        ServerMessage::read_from(&mut [42_u8].as_slice()),
+        Err(ProtocolError::UnknownTag(42))
+    ));
+}
+
+#[test]
+fn read_text_test() {
+    let text = "Hello\n\tnot allowed";
+    assert!(matches!(
+        read_text(&mut text.as_bytes(), text.len() as u32),
+        Err(ProtocolError::InvalidValue(_))
+    ));
+    let text = "caf\u{e9} \u{202E}txt.exe";
+    assert!(read_text(&mut text.as_bytes(), text.len() as u32).is_err());
+    let text = "x".repeat(MAX_TEXT_LINE_LENGTH + 1);
+    assert!(read_text(&mut text.as_bytes(), text.len() as u32).is_err());
+    let text = format!("{}\n{}", "x".repeat(MAX_TEXT_LINE_LENGTH), "caf\u{e9}");
+    assert_eq!(
+        read_text(&mut text.as_bytes(), text.len() as u32).unwrap(),
+        text
+    );
+    assert!(read_text(&mut [0xff_u8, 0xfe].as_slice(), 2).is_err());
+}
+
+#[test]
+fn page_ranges_test() {
+    let ranges = parse_page_ranges("1-3, 5,9-").unwrap();
+    assert_eq!(
+        ranges,
+        vec![
+            PageRange { first: 1, last: 3 },
+            PageRange { first: 5, last: 5 },
+            PageRange {
+                first: 9,
+                last: u16::MAX
+            },
+        ]
+    );
+    assert_eq!(selected_pages(&ranges, 10), vec![1, 2, 3, 5, 9, 10]);
+    assert_eq!(selected_pages(&ranges, 4), vec![1, 2, 3]);
+    assert_eq!(selected_pages(&[], 3), vec![1, 2, 3]);
+    assert_eq!(format_pages(&selected_pages(&ranges, 10)), "1-3,5,9-10");
+    assert_eq!(format_pages(&[]), "");
+    assert!(parse_page_ranges("").is_err());
+    assert!(parse_page_ranges("0-2").is_err());
+    assert!(parse_page_ranges("5-2").is_err());
+    assert!(parse_page_ranges("-4").is_err());
+    assert!(parse_page_ranges("a").is_err());
+}
+
+#[test]
+fn pixel_format_test() {
+    assert_eq!(PixelFormat::Rgba.payload_size(3, 2), Some(24));
+    assert_eq!(PixelFormat::Rgb.payload_size(3, 2), Some(18));
+    assert_eq!(PixelFormat::Gray.payload_size(3, 2), Some(6));
+    assert_eq!(PixelFormat::Bilevel.payload_size(9, 2), Some(4));
+    assert_eq!(PixelFormat::Rgba.payload_size(u32::MAX, u32::MAX), None);
+    let pixels = read_pixels(
+        &mut [0xff_u8, 0x80, 0, 1].as_slice(),
+        9,
+        2,
+        PixelFormat::Bilevel,
+    );
+    assert_eq!(pixels.unwrap(), vec![0xff, 0x80, 0, 1]);
+}
@@ -2,7 +2,9 @@
 #![deny(clippy::mem_forget)]
 use gio::prelude::*;
 use qubes_converter_client::{
-    convert_all_files, default_archive_folder, list_ocr_langs, ConvertEvent, ConvertParameters,
+    convert_all_files, default_archive_folder, list_ocr_langs, AudioFormat, Config, ConvertEvent,
+    ConvertParameters, ImageFormat, Isolation, OutputProfile, PageCompression, PixelFormat,
+    Resolution, DISPOSABLE_TARGET,
 };
 
 use clap::Parser;
@@ -10,7 +12,7 @@ use glib::{clone, GString, Receiver, ToValue};
 use glob::glob;
 use gtk4::prelude::*;
 use log::debug;
-use std::{fs, thread};
+use std::{collections::HashMap, fs, thread};
 
 //#[clap(setting = AppSettings::ColoredHelp)]
 #[derive(Parser)]
@@ -35,6 +37,10 @@ fn main() {
             );
         }
     }
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        Config::default()
+    });
     let ocr_languages = match list_ocr_langs() {
         Err(_) => Vec::new(),
         Ok(langs) => langs,
@@ -50,7 +56,10 @@ fn main() {
         let (backend_to_controller_transmitter, backend_to_controller_receiver) =
             std::sync::mpsc::channel();
         thread::spawn(move || {
-            convert_all_files(&backend_to_controller_transmitter, parameters).unwrap();
+            // The files it could not convert are reported as failed, with the reason.
+            if let Err(e) = convert_all_files(&backend_to_controller_transmitter, parameters) {
+                debug!("Conversion stopped: {}", e);
+            }
         });
         for event in backend_to_controller_receiver {
             controller_to_ui_transmitter.send(event).unwrap();
@@ -73,6 +82,7 @@ fn main() {
             ui_to_controller_transmitter.clone(),
             &all_files,
             &ocr_languages,
+            &config,
         );
     });
     application.run_with_args(&[""]);
@@ -83,10 +93,19 @@ fn connect_launch_button(
     follow_convert_status_window: &gtk4::ApplicationWindow,
     define_parameters_window: &gtk4::ApplicationWindow,
     in_place: bool,
-    default_password: String,
+    passwords: Vec<String>,
+    max_password_attempts: u8,
     data_from_ui: &std::sync::mpsc::Sender<ConvertParameters>,
     application: &gtk4::Application,
     ocr_language: Option<GString>,
+    resolution: Resolution,
+    pixel_format: PixelFormat,
+    output_profile: OutputProfile,
+    image_format: ImageFormat,
+    target: &str,
+    extension_targets: &HashMap<String, String>,
+    parallel_servers: u8,
+    isolation: Isolation,
 ) {
     debug!("Trying to start converting");
     let mut files = Vec::new();
@@ -108,14 +127,31 @@ fn connect_launch_button(
     data_from_ui
         .send(ConvertParameters {
             in_place,
-            default_password,
-            archive: Some(match archive {
-                Some(uri) => format!("{}/", uri),
-                None => default_archive_folder(),
-            }),
+            passwords,
+            file_passwords: HashMap::new(),
+            max_password_attempts,
+            archive: archive.map(|uri| format!("{}/", uri)),
             files,
             max_pages_converted_in_parallele: 1,
             ocr,
+            audio_format: AudioFormat::Wav,
+            image_format,
+            file_image_formats: HashMap::new(),
+            video_audio: true,
+            html_single_page: false,
+            dpi: resolution.dpi(),
+            pages: Vec::new(),
+            pixel_format,
+            page_compression: PageCompression::Lossless,
+            output_profile,
+            target: if target.is_empty() {
+                DISPOSABLE_TARGET.to_string()
+            } else {
+                target.to_string()
+            },
+            extension_targets: extension_targets.clone(),
+            parallel_servers,
+            isolation,
             stderr: true,
         })
         .unwrap();
@@ -125,6 +161,48 @@ fn connect_launch_button(
     define_parameters_window.close();
     follow_convert_status_window.show();
 }
+fn resolution_from_ui(resolution_combo: &gtk4::ComboBoxText) -> Resolution {
+    resolution_combo
+        .active_id()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(Resolution::Print)
+}
+fn pixel_format_from_ui(pixel_format_combo: &gtk4::ComboBoxText) -> PixelFormat {
+    pixel_format_combo
+        .active_id()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(PixelFormat::Rgba)
+}
+fn output_profile_from_ui(output_profile_combo: &gtk4::ComboBoxText) -> OutputProfile {
+    output_profile_combo
+        .active_id()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(OutputProfile::Standard)
+}
+fn image_format_from_ui(image_format_combo: &gtk4::ComboBoxText) -> ImageFormat {
+    image_format_combo
+        .active_id()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(ImageFormat::Png)
+}
+fn isolation_from_ui(isolation_combo: &gtk4::ComboBoxText) -> Isolation {
+    isolation_combo
+        .active_id()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(Isolation::Batch)
+}
+fn passwords_from_ui(
+    default_password: &gtk4::Entry,
+    other_passwords: &gtk4::TextView,
+) -> Vec<String> {
+    let buffer = other_passwords.buffer();
+    let (start, end) = buffer.bounds();
+    let other_passwords = buffer.text(&start, &end, false);
+    std::iter::once(default_password.text().to_string())
+        .chain(other_passwords.lines().map(str::to_string))
+        .filter(|password| !password.is_empty())
+        .collect()
+}
 fn connect_archive_folder_chooser_button(
     archive_liststore: &gtk4::ListStore,
     archive_folder_button: &gtk4::Button,
@@ -187,6 +265,7 @@ fn build_ui(
     data_from_ui: std::sync::mpsc::Sender<ConvertParameters>,
     files: &[String],
     ocr_languages: &[String],
+    config: &Config,
 ) {
     debug!("reading ui files");
     let parameters_selection_builder =
@@ -207,6 +286,15 @@ fn build_ui(
         .unwrap();
     let ocr_language_combo: gtk4::ComboBoxText =
         parameters_selection_builder.object("ocr_language").unwrap();
+    let resolution_combo: gtk4::ComboBoxText =
+        parameters_selection_builder.object("resolution").unwrap();
+    let pixel_format_combo: gtk4::ComboBoxText =
+        parameters_selection_builder.object("pixel_format").unwrap();
+    let output_profile_combo: gtk4::ComboBoxText = parameters_selection_builder
+        .object("output_profile")
+        .unwrap();
+    let image_format_combo: gtk4::ComboBoxText =
+        parameters_selection_builder.object("image_format").unwrap();
     let follow_convert_status_window: gtk4::ApplicationWindow = convert_status_progress_builder
         .object("follow_convert_status_window")
         .unwrap();
@@ -220,7 +308,21 @@ fn build_ui(
     let default_password: gtk4::Entry = parameters_selection_builder
         .object("default_password")
         .unwrap();
-    archive_folder_button.set_label(&default_archive_folder());
+    let other_passwords: gtk4::TextView = parameters_selection_builder.object("passwords").unwrap();
+    let target: gtk4::Entry = parameters_selection_builder.object("target").unwrap();
+    let parallel_servers: gtk4::SpinButton = parameters_selection_builder
+        .object("parallel_servers")
+        .unwrap();
+    let isolation_combo: gtk4::ComboBoxText =
+        parameters_selection_builder.object("isolation").unwrap();
+    let max_password_attempts: gtk4::SpinButton = parameters_selection_builder
+        .object("max_password_attempts")
+        .unwrap();
+    if let Some(config_target) = &config.target {
+        target.set_text(config_target);
+    }
+    let extension_targets = config.extension_targets.clone();
+    archive_folder_button.set_label(&default_archive_folder().unwrap_or_default());
     if !files.is_empty() {
         file_picker_button.set_label(&files.join("\n"));
         for file in files {
@@ -235,19 +337,20 @@ fn build_ui(
 
     debug!("Configuring UI events");
 
-    launch_button.connect_clicked(clone!(@weak ocr_language_combo ,@weak files_liststore, @weak archive_liststore, @weak define_parameters_window, @weak application, @weak default_password => move |_|{
-        connect_launch_button(&archive_liststore, &files_liststore, &follow_convert_status_window, &define_parameters_window, in_place.is_active(), default_password.text().to_string(), &data_from_ui, &application, ocr_language_combo.active_text());
-    }));
-
     data_to_ui.attach(
           None,
-          clone!(@weak convert_status_liststore => @default-return Continue(true), move |convert_event| {
+          clone!(@weak convert_status_liststore, @weak follow_convert_status_window => @default-return Continue(true), move |convert_event| {
               update_convert_status_gui(
                   &convert_event,
                   &convert_status_liststore,
+                  &follow_convert_status_window,
               )
           }),
       );
+
+    launch_button.connect_clicked(clone!(@weak ocr_language_combo, @weak resolution_combo, @weak pixel_format_combo, @weak output_profile_combo, @weak image_format_combo, @weak files_liststore, @weak archive_liststore, @weak define_parameters_window, @weak application, @weak default_password, @weak other_passwords, @weak target, @weak parallel_servers, @weak isolation_combo, @weak max_password_attempts => move |_|{
+        connect_launch_button(&archive_liststore, &files_liststore, &follow_convert_status_window, &define_parameters_window, in_place.is_active(), passwords_from_ui(&default_password, &other_passwords), max_password_attempts.value_as_int().try_into().unwrap_or(3), &data_from_ui, &application, ocr_language_combo.active_text(), resolution_from_ui(&resolution_combo), pixel_format_from_ui(&pixel_format_combo), output_profile_from_ui(&output_profile_combo), image_format_from_ui(&image_format_combo), target.text().trim(), &extension_targets, parallel_servers.value_as_int().try_into().unwrap_or(1), isolation_from_ui(&isolation_combo));
+    }));
     archive_folder_button.connect_clicked(clone!(@weak archive_folder_button, @weak define_parameters_window => move |_|{
          connect_archive_folder_chooser_button(&archive_liststore, &archive_folder_button, &define_parameters_window);
     }));
@@ -260,9 +363,40 @@ fn build_ui(
     define_parameters_window.show();
 }
 
+fn ask_password(
+    window: &gtk4::ApplicationWindow,
+    file: &str,
+    attempt: u8,
+    reply: std::sync::mpsc::Sender<Option<String>>,
+) {
+    let dialog = gtk4::Dialog::with_buttons(
+        Some("File protected by password"),
+        Some(window),
+        gtk4::DialogFlags::MODAL,
+        &[
+            ("Skip this file", gtk4::ResponseType::Cancel),
+            ("Ok", gtk4::ResponseType::Accept),
+        ],
+    );
+    let label = gtk4::Label::new(Some(&format!("{} (attempt {})", file, attempt)));
+    let password_entry = gtk4::PasswordEntry::new();
+    dialog.content_area().append(&label);
+    dialog.content_area().append(&password_entry);
+    dialog.connect_response(clone!(@weak password_entry => move |dialog, response| {
+        let password = if response == gtk4::ResponseType::Accept {
+            Some(password_entry.text().to_string())
+        } else {
+            None
+        };
+        let _ = reply.send(password);
+        dialog.close();
+    }));
+    dialog.show();
+}
 fn update_convert_status_gui(
     convert_event: &ConvertEvent,
     model: &gtk4::ListStore,
+    window: &gtk4::ApplicationWindow,
 ) -> glib::Continue {
     match convert_event {
         ConvertEvent::FileToConvert { file } => {
@@ -284,6 +418,7 @@ fn update_convert_status_gui(
         ConvertEvent::FileInfo {
             output_type: _,
             number_pages,
+            estimated_size: _,
             file,
         } => {
             let gtk_number_pages = u32::from(*number_pages);
@@ -341,6 +476,21 @@ fn update_convert_status_gui(
                 false
             });
         }
+        ConvertEvent::PasswordRequired {
+            file,
+            attempt,
+            reply,
+        } => {
+            model.foreach(|_tree_model, _tree_path, tree_iter| {
+                let gtk_filename: String = model.get(tree_iter, 0).get::<String>().unwrap();
+                if &gtk_filename == file {
+                    model.set_value(tree_iter, 4, &"Waiting for password".to_value());
+                    return true;
+                }
+                false
+            });
+            ask_password(window, file, *attempt, reply.clone());
+        }
     }
     Continue(true)
 }
@@ -18,16 +18,29 @@
  - https://github.com/firstlookmedia/dangerzone-converter
  Both projects can improve the other.
 */
-use image::io::Reader as ImageReader;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    imageops::FilterType,
+    io::Reader as ImageReader,
+    AnimationDecoder, GenericImage, ImageFormat,
+};
 use log::debug;
 use qubes_converter_common;
-use qubes_converter_common::OutputType;
+use qubes_converter_common::{
+    protocol::{
+        self, ClientMessage, ConversionFailure, ConvertOptions, FailureReason, PageRange,
+        PixelFormat, ProtocolError, ServerMessage,
+    },
+    OutputType,
+};
 use std::{
+    convert::TryFrom,
     fs::{self, File},
-    io::{self, prelude::*, BufRead},
+    io::{self, prelude::*},
     net::TcpStream,
+    path::{Path, PathBuf},
     process::{Child, Command, Stdio},
-    sync::mpsc::channel,
+    sync::mpsc::{channel, Receiver},
     thread, time,
 };
 use uuid::Uuid;
@@ -36,81 +49,670 @@ pub struct ProcessOutput {
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
 }
-fn strict_process_execute(binary: &str, args: &[&str]) -> ProcessOutput {
+// Run `binary` to completion, a missing tool or a non-zero exit fails the current file.
+fn strict_process_execute(
+    binary: &str,
+    args: &[&str],
+) -> Result<ProcessOutput, Box<dyn std::error::Error>> {
     debug!("{}: {:?}", binary, args);
-    let process = std::process::Command::new(binary)
+    let process = Command::new(binary)
         .args(args)
         .output()
-        .expect("Unable to start process");
+        .map_err(|e| unable_to_start(binary, &e))?;
     if !process.status.success() {
-        debug!(
-            "Following process failed: {} {:?}. Panicking ",
-            binary, args
-        );
-        debug!("{}", String::from_utf8_lossy(&process.stderr));
+        debug!("Following process failed: {} {:?}", binary, args);
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "{} failed: {}",
+                binary,
+                String::from_utf8_lossy(&process.stderr)
+            ),
+        )
+        .into());
     }
-    ProcessOutput {
+    Ok(ProcessOutput {
         stdout: process.stdout,
         stderr: process.stderr,
+    })
+}
+fn unable_to_start(binary: &str, error: &io::Error) -> Box<dyn std::error::Error> {
+    ConversionFailure::new(
+        FailureReason::RendererCrash,
+        format!("Unable to start {}: {}", binary, error),
+    )
+    .into()
+}
+fn send_message(message: &ServerMessage) -> Result<(), ProtocolError> {
+    let mut stdout = io::stdout();
+    message.write_to(&mut stdout)?;
+    stdout.flush()?;
+    Ok(())
+}
+// Same limit on the client. Every frame is sent as a full image.
+const MAX_IMAGE_FRAMES: usize = 1_000;
+
+// Frames of an animated GIF, PNG or WebP, already composited by the image library. `None` for
+// still images, and for the ones the image library can not open: GraphicsMagick tries them.
+fn animation_frames(
+    file_path: &str,
+) -> Result<Option<image::Frames<'static>>, Box<dyn std::error::Error>> {
+    let format = match ImageReader::open(file_path)
+        .ok()
+        .and_then(|reader| reader.with_guessed_format().ok())
+        .and_then(|reader| reader.format())
+    {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+    let reader = io::BufReader::new(File::open(file_path)?);
+    Ok(match format {
+        ImageFormat::Gif => GifDecoder::new(reader)
+            .ok()
+            .map(AnimationDecoder::into_frames),
+        ImageFormat::Png => match PngDecoder::new(reader) {
+            Ok(decoder) if decoder.is_apng()? => Some(decoder.apng()?.into_frames()),
+            _ => None,
+        },
+        ImageFormat::WebP => match WebPDecoder::new(reader) {
+            Ok(decoder) if decoder.has_animation() => Some(decoder.into_frames()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+fn count_frames(frames: image::Frames) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut number_frames = 0;
+    for frame in frames {
+        frame?;
+        number_frames += 1;
+        if number_frames > MAX_IMAGE_FRAMES {
+            return Err(limit_exceeded(format!(
+                "more than {} frames",
+                MAX_IMAGE_FRAMES
+            )));
+        }
+    }
+    Ok(number_frames)
+}
+// Pages of a multi-page image, like a TIFF fax, as reported by GraphicsMagick. 1 when it can not
+// tell, the image is then converted like any other.
+fn count_image_pages(file_path: &str) -> usize {
+    match Command::new("gm").args(&["identify", file_path]).output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .count()
+            .max(1),
+        _ => 1,
     }
 }
-fn convert_image(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn convert_image(
+    temporary_directory_file: &str,
+    file_path: &str,
+    options: &ConvertOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
     debug!("Start converting image");
-    let number_pages: u16 = 1;
-    io::stdout().write_all(&number_pages.to_le_bytes())?;
-    io::stdout().write_all(&[OutputType::Image as u8])?;
-    send_image(file_path)
+    if let Some(frames) = animation_frames(file_path)? {
+        let number_frames = count_frames(frames)?;
+        if number_frames > 1 {
+            return convert_animation(file_path, number_frames, options.pixel_format);
+        }
+    }
+    let number_pages = count_image_pages(file_path);
+    if number_pages > 1 {
+        return convert_image_pages(temporary_directory_file, file_path, number_pages, options);
+    }
+    send_message(&ServerMessage::FileInfo {
+        number_pages: 1,
+        output_type: OutputType::Image,
+    })?;
+    send_image(file_path, options.pixel_format)
+}
+// Animated images stay images, the client rebuilds the animation from the frames.
+fn convert_animation(
+    file_path: &str,
+    number_frames: usize,
+    pixel_format: PixelFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Animated image, {} frames", number_frames);
+    #[allow(clippy::cast_possible_truncation)]
+    send_message(&ServerMessage::FileInfo {
+        number_pages: number_frames as u16,
+        output_type: OutputType::Image,
+    })?;
+    // Decoded a second time, keeping every frame in memory is not an option.
+    let frames = animation_frames(file_path)?.ok_or_else(|| {
+        ConversionFailure::new(FailureReason::RendererCrash, "animation can not be decoded")
+    })?;
+    for frame in frames.take(number_frames) {
+        let frame = frame?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let delay_milliseconds = u16::try_from(numerator / denominator.max(1)).unwrap_or(u16::MAX);
+        send_message(&ServerMessage::AnimationFrame { delay_milliseconds })?;
+        send_page(frame.into_buffer(), pixel_format)?;
+    }
+    fs::remove_file(file_path)?;
+    Ok(())
 }
-fn convert_to_png_and_open(file_path: &str) -> image::DynamicImage {
+// Pages of a multi-page image are a document, sent like the pages of a PDF.
+fn convert_image_pages(
+    temporary_directory_file: &str,
+    file_path: &str,
+    number_pages: usize,
+    options: &ConvertOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Multi-page image, {} pages", number_pages);
+    if number_pages > MAX_IMAGE_FRAMES {
+        return Err(limit_exceeded(format!(
+            "{} pages, at most {} are accepted",
+            number_pages, MAX_IMAGE_FRAMES
+        )));
+    }
+    strict_process_execute(
+        "gm",
+        &[
+            "convert",
+            file_path,
+            "+adjoin",
+            &format!("png:{}/image_page_%05d.png", temporary_directory_file),
+        ],
+    )?;
+    let mut pages: Vec<PathBuf> =
+        glob::glob(&format!("{}/image_page_*.png", temporary_directory_file))
+            .expect("Failed to read glob pattern")
+            .collect::<Result<_, _>>()?;
+    pages.sort();
+    pages.truncate(MAX_IMAGE_FRAMES);
+    let pages = send_selected_pages_info(pages, &options.pages)?;
+    for page in &pages {
+        send_image(page.to_str().unwrap(), options.pixel_format)?;
+    }
+    fs::remove_file(file_path)?;
+    Ok(())
+}
+fn convert_to_png_and_open(
+    file_path: &str,
+) -> Result<image::DynamicImage, Box<dyn std::error::Error>> {
     let png_file = format!("{}.png", file_path);
-    strict_process_execute("gm", &["convert", file_path, &format!("png:{}", png_file)]);
-    ImageReader::open(png_file)
-        .unwrap()
-        .with_guessed_format()
-        .unwrap()
-        .decode()
-        .unwrap()
-}
-fn send_image(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    strict_process_execute("gm", &["convert", file_path, &format!("png:{}", png_file)])?;
+    Ok(ImageReader::open(png_file)?
+        .with_guessed_format()?
+        .decode()?)
+}
+fn send_image(
+    file_path: &str,
+    pixel_format: PixelFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
     debug!("Start send_image: {}", file_path);
     // Try to open the image with the image rust library. If it fail, convert it to png with GM and
     // retry.
     let png_image = match ImageReader::open(file_path)?.with_guessed_format() {
         Ok(img) => match img.decode() {
             Ok(supported) => supported,
-            Err(_) => convert_to_png_and_open(file_path),
+            Err(_) => convert_to_png_and_open(file_path)?,
         },
-        Err(_) => convert_to_png_and_open(file_path),
+        Err(_) => convert_to_png_and_open(file_path)?,
     };
-    let rgba = png_image.into_rgba8();
-    #[allow(clippy::cast_possible_truncation)]
-    let height = rgba.height() as u16;
-    #[allow(clippy::cast_possible_truncation)]
-    let width = rgba.width() as u16;
-    io::stdout().write_all(&width.to_le_bytes())?;
-    io::stdout().write_all(&height.to_le_bytes())?;
-    io::stdout().write_all(&rgba)?;
+    send_page(png_image.into_rgba8(), pixel_format)?;
     fs::remove_file(&file_path)?;
     Ok(())
 }
+// Images bigger than what the client accepts are scaled down, always the same way and keeping
+// their aspect ratio, so every frame of an animation still has the same size.
+fn fit_page(rgba: image::RgbaImage) -> image::RgbaImage {
+    if rgba.width() <= protocol::MAX_PAGE_WIDTH && rgba.height() <= protocol::MAX_PAGE_HEIGHT {
+        return rgba;
+    }
+    debug!("Scaling down a {}x{} image", rgba.width(), rgba.height());
+    image::DynamicImage::ImageRgba8(rgba)
+        .resize(
+            protocol::MAX_PAGE_WIDTH,
+            protocol::MAX_PAGE_HEIGHT,
+            FilterType::Triangle,
+        )
+        .into_rgba8()
+}
+// Pixels darker than this become black in `PixelFormat::Bilevel`.
+const BILEVEL_THRESHOLD: u8 = 128;
 
+// Formats without alpha show transparent pixels on white, like a page.
+fn flatten_on_white(rgba: &image::RgbaImage) -> image::RgbImage {
+    image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [red, green, blue, alpha] = rgba.get_pixel(x, y).0;
+        #[allow(clippy::cast_possible_truncation)]
+        let blend = |color: u8| {
+            ((u32::from(color) * u32::from(alpha) + 255 * u32::from(255 - alpha)) / 255) as u8
+        };
+        image::Rgb([blend(red), blend(green), blend(blue)])
+    })
+}
+fn pack_bilevel(gray: &image::GrayImage) -> Vec<u8> {
+    let row_size = (gray.width() as usize).div_ceil(8);
+    let mut packed = vec![0; row_size * gray.height() as usize];
+    for (x, y, pixel) in gray.enumerate_pixels() {
+        if pixel.0[0] >= BILEVEL_THRESHOLD {
+            packed[y as usize * row_size + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    packed
+}
+fn send_page(
+    rgba: image::RgbaImage,
+    pixel_format: PixelFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rgba = fit_page(rgba);
+    let (width, height) = rgba.dimensions();
+    let pixels = match pixel_format {
+        PixelFormat::Rgba => rgba.into_raw(),
+        PixelFormat::Rgb => flatten_on_white(&rgba).into_raw(),
+        PixelFormat::Gray => image::DynamicImage::ImageRgb8(flatten_on_white(&rgba))
+            .into_luma8()
+            .into_raw(),
+        PixelFormat::Bilevel => {
+            pack_bilevel(&image::DynamicImage::ImageRgb8(flatten_on_white(&rgba)).into_luma8())
+        }
+    };
+    let mut stdout = io::stdout();
+    ServerMessage::Page {
+        width,
+        height,
+        format: pixel_format,
+    }
+    .write_to(&mut stdout)?;
+    stdout.write_all(&pixels)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+// The client rebuilds the audio file from these samples only. Anything above is resampled or
+// downmixed, no need to transfer inaudible details.
+const MAX_AUDIO_SAMPLE_RATE: u32 = 48_000;
+const MAX_AUDIO_CHANNELS: u8 = 2;
+// 10 seconds at the maximum sample rate, also used as the unit of progress.
+const AUDIO_CHUNK_FRAMES: u32 = MAX_AUDIO_SAMPLE_RATE * 10;
+
+fn probe_audio(file_path: &str) -> Result<(u32, u8), Box<dyn std::error::Error>> {
+    let output = strict_process_execute(
+        "ffprobe",
+        &[
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "stream=sample_rate,channels",
+            "-of",
+            "default=noprint_wrappers=1",
+            file_path,
+        ],
+    )?;
+    let mut sample_rate = None;
+    let mut channels = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.split_once('=') {
+            Some(("sample_rate", value)) => sample_rate = value.parse::<u32>().ok(),
+            Some(("channels", value)) => channels = value.parse::<u8>().ok(),
+            _ => {}
+        }
+    }
+    match (sample_rate, channels) {
+        (Some(sample_rate), Some(channels)) if sample_rate > 0 && channels > 0 => Ok((
+            sample_rate.min(MAX_AUDIO_SAMPLE_RATE),
+            channels.min(MAX_AUDIO_CHANNELS),
+        )),
+        _ => Err(
+            ConversionFailure::new(FailureReason::UnsupportedType, "No audio stream found").into(),
+        ),
+    }
+}
+// Samples of an audio track, decoded to raw PCM next to the untrusted file.
+struct DecodedAudio {
+    pcm_file: String,
+    sample_rate: u32,
+    channels: u8,
+    total_frames: u64,
+    number_chunks: u16,
+}
+fn decode_audio(
+    source_file: &str,
+    pcm_file: &str,
+) -> Result<DecodedAudio, Box<dyn std::error::Error>> {
+    let (sample_rate, channels) = probe_audio(source_file)?;
+    let ffmpeg_process = Command::new("ffmpeg")
+        .args(&[
+            "-nostdin",
+            "-v",
+            "error",
+            "-i",
+            source_file,
+            "-vn",
+            "-f",
+            "s16le",
+            "-acodec",
+            "pcm_s16le",
+            "-ar",
+            &sample_rate.to_string(),
+            "-ac",
+            &channels.to_string(),
+            "-y",
+            pcm_file,
+        ])
+        .output()?;
+    if !ffmpeg_process.status.success() {
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "ffmpeg failed: {}",
+                String::from_utf8_lossy(&ffmpeg_process.stderr)
+            ),
+        )
+        .into());
+    }
+    let frame_size = u64::from(channels) * 2;
+    let total_frames = fs::metadata(pcm_file)?.len() / frame_size;
+    let number_chunks = u16::try_from(total_frames.div_ceil(u64::from(AUDIO_CHUNK_FRAMES)))
+        .map_err(|_| ConversionFailure::new(FailureReason::LimitExceeded, "Audio file too long"))?;
+    debug!(
+        "{} frames at {} Hz, {} channels",
+        total_frames, sample_rate, channels
+    );
+    Ok(DecodedAudio {
+        pcm_file: pcm_file.to_string(),
+        sample_rate,
+        channels,
+        total_frames,
+        number_chunks,
+    })
+}
+fn send_audio(audio: &DecodedAudio) -> Result<(), Box<dyn std::error::Error>> {
+    send_message(&ServerMessage::AudioFormat {
+        sample_rate: audio.sample_rate,
+        channels: audio.channels,
+    })?;
+    let frame_size = u64::from(audio.channels) * 2;
+    let mut pcm = io::BufReader::new(File::open(&audio.pcm_file)?);
+    let mut remaining_frames = audio.total_frames;
+    let mut stdout = io::stdout();
+    for _ in 0..audio.number_chunks {
+        let number_frames = remaining_frames.min(u64::from(AUDIO_CHUNK_FRAMES));
+        remaining_frames -= number_frames;
+        #[allow(clippy::cast_possible_truncation)]
+        let mut buffer = vec![0; (number_frames * frame_size) as usize];
+        pcm.read_exact(&mut buffer)?;
+        #[allow(clippy::cast_possible_truncation)]
+        ServerMessage::AudioChunk {
+            number_frames: number_frames as u32,
+        }
+        .write_to(&mut stdout)?;
+        stdout.write_all(&buffer)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+fn convert_audio(temporary_directory_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Start converting audio");
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let audio = decode_audio(
+        &source_file,
+        &format!("{}/audio.pcm", temporary_directory_file),
+    )?;
+    send_message(&ServerMessage::FileInfo {
+        number_pages: audio.number_chunks,
+        output_type: OutputType::Audio,
+    })?;
+    send_audio(&audio)
+}
+
+// Frames are sent uncompressed, keep them small enough that a long video stays transferable.
+const MAX_VIDEO_WIDTH: u32 = 1920;
+const MAX_VIDEO_HEIGHT: u32 = 1080;
+const MAX_VIDEO_FPS: u8 = 30;
+
+struct VideoProbe {
+    width: u32,
+    height: u32,
+    frames_per_second: u8,
+    duration_seconds: u64,
+}
+// "30000/1001" or "25/1"
+fn parse_frame_rate(value: &str) -> Option<f64> {
+    let (numerator, denominator) = value.split_once('/')?;
+    let numerator = numerator.parse::<f64>().ok()?;
+    let denominator = denominator.parse::<f64>().ok()?;
+    if denominator > 0.0 {
+        Some(numerator / denominator)
+    } else {
+        None
+    }
+}
+fn probe_video(file_path: &str) -> Result<VideoProbe, Box<dyn std::error::Error>> {
+    let output = strict_process_execute(
+        "ffprobe",
+        &[
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=width,height,avg_frame_rate:format=duration",
+            "-of",
+            "default=noprint_wrappers=1",
+            file_path,
+        ],
+    )?;
+    let mut width = None;
+    let mut height = None;
+    let mut frame_rate = None;
+    let mut duration = None;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.split_once('=') {
+            Some(("width", value)) => width = value.parse::<u32>().ok(),
+            Some(("height", value)) => height = value.parse::<u32>().ok(),
+            Some(("avg_frame_rate", value)) => frame_rate = parse_frame_rate(value),
+            Some(("duration", value)) => duration = value.parse::<f64>().ok(),
+            _ => {}
+        }
+    }
+    match (width, height) {
+        (Some(width), Some(height)) if width > 0 && height > 0 => {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let frames_per_second = frame_rate
+                .map_or(f64::from(MAX_VIDEO_FPS), f64::round)
+                .clamp(1.0, f64::from(MAX_VIDEO_FPS)) as u8;
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            let duration_seconds = duration.unwrap_or(0.0).max(0.0).ceil() as u64;
+            Ok(VideoProbe {
+                width,
+                height,
+                frames_per_second,
+                duration_seconds,
+            })
+        }
+        _ => Err(
+            ConversionFailure::new(FailureReason::UnsupportedType, "No video stream found").into(),
+        ),
+    }
+}
+// Fit the video in MAX_VIDEO_WIDTH x MAX_VIDEO_HEIGHT keeping its aspect ratio. Encoders want
+// even dimensions.
+fn video_output_size(width: u32, height: u32) -> (u16, u16) {
+    let (width, height) = if width > MAX_VIDEO_WIDTH || height > MAX_VIDEO_HEIGHT {
+        if u64::from(width) * u64::from(MAX_VIDEO_HEIGHT)
+            > u64::from(height) * u64::from(MAX_VIDEO_WIDTH)
+        {
+            (MAX_VIDEO_WIDTH, height * MAX_VIDEO_WIDTH / width)
+        } else {
+            (width * MAX_VIDEO_HEIGHT / height, MAX_VIDEO_HEIGHT)
+        }
+    } else {
+        (width, height)
+    };
+    #[allow(clippy::cast_possible_truncation)]
+    ((width & !1).max(2) as u16, (height & !1).max(2) as u16)
+}
+fn convert_video(
+    temporary_directory_file: &str,
+    with_audio: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Start converting video");
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let video = probe_video(&source_file)?;
+    let (width, height) = video_output_size(video.width, video.height);
+    let audio = if with_audio {
+        // A video without sound is still a video.
+        decode_audio(
+            &source_file,
+            &format!("{}/audio.pcm", temporary_directory_file),
+        )
+        .ok()
+    } else {
+        None
+    };
+    debug!(
+        "{}x{} at {} fps, {} seconds",
+        width, height, video.frames_per_second, video.duration_seconds
+    );
+    send_message(&ServerMessage::FileInfo {
+        number_pages: u16::try_from(video.duration_seconds.max(1)).unwrap_or(u16::MAX),
+        output_type: OutputType::Video,
+    })?;
+    send_message(&ServerMessage::VideoFormat {
+        width,
+        height,
+        frames_per_second: video.frames_per_second,
+        audio_chunks: audio.as_ref().map_or(0, |audio| audio.number_chunks),
+    })?;
+    if let Some(audio) = &audio {
+        send_audio(audio)?;
+    }
+
+    let mut ffmpeg_process = Command::new("ffmpeg")
+        .args(&[
+            "-nostdin",
+            "-v",
+            "error",
+            "-i",
+            &source_file,
+            "-an",
+            "-vf",
+            &format!("fps={},scale={}:{}", video.frames_per_second, width, height),
+            "-f",
+            "rawvideo",
+            "-pix_fmt",
+            "rgb24",
+            "-",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut frames = ffmpeg_process.stdout.take().unwrap();
+    let mut buffer = vec![0; usize::from(width) * usize::from(height) * 3];
+    let mut stdout = io::stdout();
+    let mut number_frames: u64 = 0;
+    loop {
+        match frames.read_exact(&mut buffer) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        ServerMessage::VideoFrame.write_to(&mut stdout)?;
+        stdout.write_all(&buffer)?;
+        stdout.flush()?;
+        number_frames += 1;
+    }
+    let ffmpeg_output = ffmpeg_process.wait_with_output()?;
+    debug!("{} frames sent", number_frames);
+    if !ffmpeg_output.status.success() || number_frames == 0 {
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "ffmpeg failed after {} frames: {}",
+                number_frames,
+                String::from_utf8_lossy(&ffmpeg_output.stderr)
+            ),
+        )
+        .into());
+    }
+    send_message(&ServerMessage::VideoEnd)?;
+    Ok(())
+}
+
+// Pages selected by the client among the `total_pages` of a document.
+fn select_pages(
+    total_pages: usize,
+    ranges: &[PageRange],
+) -> Result<Vec<u16>, Box<dyn std::error::Error>> {
+    let total_pages = u16::try_from(total_pages).map_err(|_| {
+        limit_exceeded(format!(
+            "{} pages, the protocol is limited to {}",
+            total_pages,
+            u16::MAX
+        ))
+    })?;
+    let selected = protocol::selected_pages(ranges, total_pages);
+    if selected.is_empty() && total_pages > 0 {
+        return Err(ConversionFailure::new(
+            FailureReason::EmptySelection,
+            format!("the document only has {} pages", total_pages),
+        )
+        .into());
+    }
+    Ok(selected)
+}
+fn send_pages_info(
+    number_selected: usize,
+    total_pages: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[allow(clippy::cast_possible_truncation)]
+    send_message(&ServerMessage::FileInfo {
+        number_pages: number_selected as u16,
+        output_type: OutputType::Pdf,
+    })?;
+    #[allow(clippy::cast_possible_truncation)]
+    send_message(&ServerMessage::PageSelection {
+        total_pages: total_pages as u16,
+    })?;
+    Ok(())
+}
+// Announce the pages selected by the client among `pages`, every page of the document in order.
+// The files of the other pages are removed, they are never sent.
+fn send_selected_pages_info<P: AsRef<Path>>(
+    pages: Vec<P>,
+    ranges: &[PageRange],
+) -> Result<Vec<P>, Box<dyn std::error::Error>> {
+    let total_pages = pages.len();
+    let selected = select_pages(total_pages, ranges)?;
+    let mut selected_pages = Vec::with_capacity(selected.len());
+    for (page, path) in (1..).zip(pages) {
+        if selected.binary_search(&page).is_ok() {
+            selected_pages.push(path);
+        } else {
+            fs::remove_file(path)?;
+        }
+    }
+    send_pages_info(selected_pages.len(), total_pages)?;
+    Ok(selected_pages)
+}
 fn convert_pdf(
     temporary_directory_file: &str,
-    default_password: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     debug!("Start getting password");
-    split_pdf_into_pages(temporary_directory_file, default_password);
+    let total_pages = split_pdf_into_pages(temporary_directory_file, passwords, &options.pages)?;
     let pages_name_paths = glob::glob(&format!("{}/pg_*.pdf", temporary_directory_file))
         .expect("Failed to read glob pattern");
     let pages_name: Vec<String> = pages_name_paths
         .map(|x| x.expect("glob failure").to_str().unwrap().to_string())
         .collect();
     debug!("number of pages: {}", pages_name.len());
+    send_pages_info(pages_name.len(), total_pages)?;
     #[allow(clippy::cast_possible_truncation)]
-    io::stdout().write_all(&(pages_name.len() as u16).to_le_bytes())?;
-    io::stdout().write_all(&[OutputType::Pdf as u8])?;
+    let number_pages = pages_name.len() as u16;
 
     let temporary_directory_file_thread = temporary_directory_file.to_string();
+    let resolution = options.dpi.to_string();
     let (tx, rx) = channel();
     thread::spawn(move || {
         let max_number_pdftocairo_process = 25;
@@ -123,20 +725,25 @@ fn convert_pdf(
                 let mut continous_chain_of_terminated_process = true;
                 let mut to_delete = Vec::new();
                 for (pngfilename, pdftocairo_process) in &mut pages {
-                    match pdftocairo_process
-                        .try_wait()
-                        .expect("pdftocairo process failed")
-                    {
-                        None => {
+                    match pdftocairo_process.try_wait() {
+                        Ok(None) => {
                             number_pdftocairo_process += 1;
                             continous_chain_of_terminated_process = false;
                         }
-                        Some(_) => {
+                        Ok(Some(status)) => {
                             if continous_chain_of_terminated_process {
                                 to_delete.push((*pngfilename).to_string());
-                                tx.send((*pngfilename).to_string()).unwrap();
+                                let rendered = rendered_page_result(pngfilename, status.success());
+                                let failed = rendered.is_err();
+                                if tx.send(rendered).is_err() || failed {
+                                    return;
+                                }
                             }
                         }
+                        Err(e) => {
+                            let _ = tx.send(Err(format!("pdftocairo process failed: {}", e)));
+                            return;
+                        }
                     }
                 }
                 pages.retain(|x| !to_delete.contains(&x.0));
@@ -151,103 +758,272 @@ fn convert_pdf(
                 .to_str()
                 .unwrap()
                 .to_string();
-            let pdftocairo_process = Command::new("pdftocairo")
-                .args(&[&path, "-png", "-singlefile"])
+            let pdftocairo_process = match Command::new("pdftocairo")
+                .args(&[&path, "-png", "-singlefile", "-r", &resolution])
                 .current_dir(&temporary_directory_file_thread)
                 .spawn()
-                .expect("Unable to launch pdftocairo process");
+            {
+                Ok(process) => process,
+                Err(e) => {
+                    let _ = tx.send(Err(format!("Unable to launch pdftocairo process: {}", e)));
+                    return;
+                }
+            };
             pages.push((pngfilename, pdftocairo_process));
             number_pdftocairo_process += 1;
         }
         for (pngfilename, mut pdftocairoprocess) in pages {
-            if !pdftocairoprocess.wait().unwrap().success() {
-                panic!("pdftocairo process failed");
+            let success = pdftocairoprocess
+                .wait()
+                .map(|status| status.success())
+                .unwrap_or(false);
+            let rendered = rendered_page_result(&pngfilename, success);
+            let failed = rendered.is_err();
+            if tx.send(rendered).is_err() || failed {
+                return;
             }
-            tx.send(pngfilename.to_string()).unwrap();
         }
     });
 
     debug!("Start converting PDF pages");
+    let mut number_pages_sent = 0;
     while let Ok(png_page) = rx.recv() {
+        let png_page = png_page
+            .map_err(|message| ConversionFailure::new(FailureReason::RendererCrash, message))?;
         debug!("sending {}", png_page);
-        send_image(&format!("{}/{}.png", temporary_directory_file, png_page))?;
+        send_image(
+            &format!("{}/{}.png", temporary_directory_file, png_page),
+            options.pixel_format,
+        )?;
+        number_pages_sent += 1;
+    }
+    if number_pages_sent != number_pages {
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "Only {} of {} pages have been rendered",
+                number_pages_sent, number_pages
+            ),
+        )
+        .into());
     }
     Ok(())
 }
+fn rendered_page_result(pngfilename: &str, success: bool) -> Result<String, String> {
+    if success {
+        Ok(pngfilename.to_string())
+    } else {
+        Err(format!("pdftocairo failed to render {}", pngfilename))
+    }
+}
 
-fn prompt_password() -> String {
-    let output = strict_process_execute(
-        "zenity",
-        &["--title", "File protected by password", "--password"],
-    );
-    return String::from_utf8(output.stdout)
-        .expect("Password contains non-utf8 chars, should be impossible")
-        .lines()
-        .next()
-        .unwrap()
-        .to_string();
+/// Password negotiation with the client for the file currently being converted.
+struct PasswordPrompt<'a> {
+    file_id: u16,
+    candidates: Vec<String>,
+    max_attempts: u8,
+    answers: &'a Receiver<Option<String>>,
+}
+impl PasswordPrompt<'_> {
+    /// Try without password, then every candidate, then ask the client for new ones until
+    /// `try_password` accepts one or the attempts are exhausted.
+    fn unlock(
+        &self,
+        mut try_password: impl FnMut(&str) -> Result<bool, Box<dyn std::error::Error>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for password in std::iter::once("").chain(self.candidates.iter().map(String::as_str)) {
+            if try_password(password)? {
+                return Ok(());
+            }
+        }
+        for attempt in 1..=self.max_attempts {
+            debug!("Asking the client for a password, attempt {}", attempt);
+            send_message(&ServerMessage::PasswordRequest {
+                file: self.file_id,
+                attempt,
+            })?;
+            let password = self.answers.recv()?.ok_or_else(|| {
+                ConversionFailure::new(FailureReason::WrongPassword, "No password provided")
+            })?;
+            if try_password(&password)? {
+                return Ok(());
+            }
+        }
+        Err(ConversionFailure::new(
+            FailureReason::WrongPassword,
+            format!("No valid password after {} attempts", self.max_attempts),
+        )
+        .into())
+    }
 }
 const TO_CONVERT_FILENAME: &str = "to_convert";
-fn split_pdf_into_pages(temporary_directory_file: &str, password: &str) {
+const SELECTED_PAGES_FILENAME: &str = "selected_pages.pdf";
+// Burst the pages selected by `ranges` into one PDF each, the others are never extracted nor
+// rendered. Returns the number of pages of the whole document.
+fn split_pdf_into_pages(
+    temporary_directory_file: &str,
+    passwords: &PasswordPrompt,
+    ranges: &[PageRange],
+) -> Result<usize, Box<dyn std::error::Error>> {
     let to_split = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let mut unlocked = None;
+    passwords.unlock(|password| {
+        unlocked = count_pdf_pages(&to_split, password)?.map(|total| (password.to_string(), total));
+        Ok(unlocked.is_some())
+    })?;
+    let (password, total_pages) = unlocked.ok_or_else(|| {
+        ConversionFailure::new(FailureReason::WrongPassword, "The PDF is still locked")
+    })?;
+    let selected = select_pages(total_pages, ranges)?;
+    if selected.len() == total_pages {
+        return run_pdftk(
+            temporary_directory_file,
+            &[&to_split, "input_pw", &password, "burst"],
+        )
+        .map(|_| total_pages);
+    }
+    // pdftk takes the ranges as separate arguments, like "1-10 45".
+    let selected_ranges = protocol::format_pages(&selected);
+    let mut args = vec![to_split.as_str(), "input_pw", &password, "cat"];
+    args.extend(selected_ranges.split(','));
+    args.extend(["output", SELECTED_PAGES_FILENAME]);
+    run_pdftk(temporary_directory_file, &args)?;
+    run_pdftk(
+        temporary_directory_file,
+        &[SELECTED_PAGES_FILENAME, "burst"],
+    )?;
+    fs::remove_file(format!(
+        "{}/{}",
+        temporary_directory_file, SELECTED_PAGES_FILENAME
+    ))?;
+    Ok(total_pages)
+}
+// Pages of the PDF, `None` when the password is wrong.
+fn count_pdf_pages(
+    file_path: &str,
+    password: &str,
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
     let pdftk_process = Command::new("pdftk")
-        .args(&[&to_split, "input_pw", password, "burst"])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
+        .args([file_path, "input_pw", password, "dump_data_utf8"])
+        .output()
+        .map_err(|e| unable_to_start("pdftk", &e))?;
+    if !pdftk_process.status.success() {
+        debug!(
+            "pdftk stderr: {}",
+            String::from_utf8_lossy(&pdftk_process.stderr)
+        );
+        return Ok(None);
+    }
+    String::from_utf8_lossy(&pdftk_process.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("NumberOfPages: "))
+        .and_then(|number| number.trim().parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            ConversionFailure::new(FailureReason::RendererCrash, "pdftk found no page count").into()
+        })
+}
+fn run_pdftk(
+    temporary_directory_file: &str,
+    args: &[&str],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pdftk_process = Command::new("pdftk")
+        .args(args)
         .current_dir(temporary_directory_file)
         .output()
-        .expect("Unable to start pdfinfo process");
-    debug!(
-        "pdftk stderr: {}",
-        String::from_utf8_lossy(&pdftk_process.stderr)
-    );
+        .map_err(|e| unable_to_start("pdftk", &e))?;
     if !pdftk_process.status.success() {
-        let password = prompt_password();
-        split_pdf_into_pages(temporary_directory_file, &password);
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "pdftk failed: {}",
+                String::from_utf8_lossy(&pdftk_process.stderr)
+            ),
+        )
+        .into());
     }
+    Ok(())
 }
 fn convert_office(
     temporary_directory: &str,
-    default_password: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     if convert_office_file_to_pdf_without_password(temporary_directory)? {
-        convert_pdf(temporary_directory, default_password)?;
+        convert_pdf(temporary_directory, passwords, options)?;
         return Ok(());
     }
 
     debug!("Launching the libreoffice server");
     let port = 2202;
-    Command::new("libreoffice")
+    let mut libreoffice_server = Command::new("libreoffice")
         .args(&[
             &format!("--accept=socket,host=localhost,port={};urp", port),
             "--headless",
         ])
         .stdout(Stdio::piped())
         .spawn()
-        .expect("Unable to start libreoffice server process");
+        .map_err(|e| unable_to_start("libreoffice", &e))?;
     let one_seconds = time::Duration::from_millis(1_000);
     while TcpStream::connect(&format!("127.0.0.1:{}", port)).is_err() {
+        if let Some(status) = libreoffice_server.try_wait()? {
+            return Err(ConversionFailure::new(
+                FailureReason::RendererCrash,
+                format!("libreoffice server exited with {}", status),
+            )
+            .into());
+        }
         thread::sleep(one_seconds);
     }
     debug!("Libreoffice server seems up and ready");
     let no_password_file = format!("{}/{}.nopassword", temporary_directory, TO_CONVERT_FILENAME);
     let source_file = format!("{}/{}", temporary_directory, TO_CONVERT_FILENAME);
-    let mut is_success = if default_password.is_empty() {
-        false
-    } else {
-        decrypt_office_file(&source_file, &no_password_file, port, default_password)
-    };
-    while !is_success {
-        let password = prompt_password();
-        is_success = decrypt_office_file(&source_file, &no_password_file, port, &password);
-    }
-    fs::rename(&no_password_file, &source_file).unwrap();
+    passwords.unlock(|password| {
+        Ok(!password.is_empty()
+            && decrypt_office_file(&source_file, &no_password_file, port, password)?)
+    })?;
+    fs::rename(&no_password_file, &source_file)?;
     if !convert_office_file_to_pdf_without_password(temporary_directory)? {
-        panic!("Conversion should have succeeded ! Abording");
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            "LibreOffice was unable to convert the decrypted file",
+        )
+        .into());
     }
-    convert_pdf(temporary_directory, default_password)
+    convert_pdf(temporary_directory, passwords, options)
 }
-fn decrypt_office_file(file_path: &str, no_password_file: &str, port: u16, password: &str) -> bool {
+// The password is read from stdin, so that it is never part of the script.
+const DECRYPT_OFFICE_SCRIPT: &str = r#"
+import sys, uno
+
+src = "file://" + sys.argv[1]
+dst = "file://" + sys.argv[2]
+port = sys.argv[3]
+
+local_context = uno.getComponentContext()
+resolver = local_context.ServiceManager.createInstanceWithContext("com.sun.star.bridge.UnoUrlResolver",local_context)
+ctx = resolver.resolve("uno:socket,host=localhost,port=%s;urp;StarOffice.ComponentContext" % port)
+smgr = ctx.ServiceManager
+desktop = smgr.createInstanceWithContext("com.sun.star.frame.Desktop", ctx)
+
+hidden_property = uno.createUnoStruct("com.sun.star.beans.PropertyValue")
+hidden_property.Name = "Hidden"
+hidden_property.Value = True
+
+password_property = uno.createUnoStruct("com.sun.star.beans.PropertyValue")
+password_property.Name = "Password"
+password_property.Value = sys.stdin.read()
+
+document = desktop.loadComponentFromURL(src,"_blank",0,(password_property, hidden_property,))
+document.storeAsURL(dst, ())
+"#;
+// `false` when the password is wrong.
+fn decrypt_office_file(
+    file_path: &str,
+    no_password_file: &str,
+    port: u16,
+    password: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
     /*
         Try to remove the password of a libreoffice-compatible file,
         and store the resulting file in INITIAL_NAME.nopassword.
@@ -259,39 +1035,21 @@ fn decrypt_office_file(file_path: &str, no_password_file: &str, port: u16, passw
         - Store the document without additionnal properties [this remove the password]
     */
     debug!("Trying to remove password from {}", file_path);
-    let python_process = Command::new("python3")
-        .args(&[
-            "-c",
-            &format!(
-                "
-import uno
-
-src = \"file://{}\"
-dst = \"file://{}\"
-
-local_context = uno.getComponentContext()
-resolver = local_context.ServiceManager.createInstanceWithContext(\"com.sun.star.bridge.UnoUrlResolver\",local_context)
-ctx = resolver.resolve(\"uno:socket,host=localhost,port={};urp;StarOffice.ComponentContext\")
-smgr = ctx.ServiceManager
-desktop = smgr.createInstanceWithContext(\"com.sun.star.frame.Desktop\", ctx)
-
-hidden_property = uno.createUnoStruct(\"com.sun.star.beans.PropertyValue\")
-hidden_property.Name = \"Hidden\"
-hidden_property.Value = True
-
-password_property = uno.createUnoStruct(\"com.sun.star.beans.PropertyValue\")
-password_property.Name = \"Password\"
-password_property.Value = \"{}\"
-
-document = desktop.loadComponentFromURL(src,\"_blank\",0,(password_property, hidden_property,))
-document.storeAsURL(dst, ())",
-                file_path, no_password_file, port, password
-            ),
-        ])
+    let mut python_process = Command::new("python3")
+        .arg("-c")
+        .arg(DECRYPT_OFFICE_SCRIPT)
+        .args([file_path, no_password_file, &port.to_string()])
+        .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .output()
-        .expect("Unable to start python process");
-    python_process.status.success()
+        .spawn()
+        .map_err(|e| unable_to_start("python3", &e))?;
+    // Closing stdin tells the script the password is complete.
+    let password_sent = match python_process.stdin.take() {
+        Some(mut stdin) => stdin.write_all(password.as_bytes()).is_ok(),
+        None => false,
+    };
+    let output = python_process.wait_with_output()?;
+    Ok(password_sent && output.status.success())
 }
 fn convert_office_file_to_pdf_without_password(
     temporary_directory: &str,
@@ -312,17 +1070,18 @@ fn convert_office_file_to_pdf_without_password(
     5: Convert the office file to PDF
     */
     let file_path = format!("{}/{}", temporary_directory, TO_CONVERT_FILENAME);
-    strict_process_execute(
-        "libreoffice",
-        &[
+    // A failed conversion is expected for encrypted files, only the converted file tells.
+    Command::new("libreoffice")
+        .args([
             "--headless",
             "--convert-to",
             "pdf",
             &file_path,
             "--outdir",
             temporary_directory,
-        ],
-    );
+        ])
+        .output()
+        .map_err(|e| unable_to_start("libreoffice", &e))?;
     let converted_file = format!("{}.pdf", file_path);
     if std::path::Path::new(&converted_file).exists() {
         fs::rename(&converted_file, file_path)?;
@@ -330,54 +1089,804 @@ fn convert_office_file_to_pdf_without_password(
     }
     Ok(false)
 }
+// Archives are unpacked in the DispVM, then every member goes through the normal converters.
+const MAX_ARCHIVE_MEMBERS: usize = 1_000;
+const MAX_ARCHIVE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+// An archive in an archive in an archive is the deepest accepted.
+const MAX_ARCHIVE_DEPTH: u8 = 3;
+
+fn is_archive(mimetype: &mime::Mime) -> bool {
+    mimetype.type_() == mime::APPLICATION
+        && matches!(
+            mimetype.subtype().as_str(),
+            "zip" | "x-tar" | "x-7z-compressed" | "gzip" | "x-bzip2" | "x-xz" | "x-compressed-tar"
+        )
+}
+fn limit_exceeded(message: impl Into<String>) -> Box<dyn std::error::Error> {
+    ConversionFailure::new(FailureReason::LimitExceeded, message).into()
+}
+// Check the sizes announced by the archive before unpacking anything, so an archive bomb does not
+// fill the disk. The real sizes are checked again once unpacked.
+fn check_archive_listing(file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let listing = Command::new("bsdtar").args(&["-tvf", file_path]).output()?;
+    if !listing.status.success() {
+        return Err(ConversionFailure::new(
+            FailureReason::UnsupportedType,
+            format!(
+                "Unable to read the archive: {}",
+                String::from_utf8_lossy(&listing.stderr)
+            ),
+        )
+        .into());
+    }
+    let mut number_members = 0;
+    let mut total_size: u64 = 0;
+    for line in String::from_utf8_lossy(&listing.stdout).lines() {
+        // "-rw-r--r--  0 user group 1234 Jan  1  2020 name"
+        let size = line
+            .split_whitespace()
+            .nth(4)
+            .and_then(|size| size.parse::<u64>().ok())
+            .unwrap_or(0);
+        number_members += 1;
+        total_size = total_size.saturating_add(size);
+    }
+    if number_members > MAX_ARCHIVE_MEMBERS {
+        return Err(limit_exceeded(format!(
+            "{} archive members, at most {} are accepted",
+            number_members, MAX_ARCHIVE_MEMBERS
+        )));
+    }
+    if total_size > MAX_ARCHIVE_SIZE {
+        return Err(limit_exceeded(format!(
+            "Archive content is {} bytes, at most {} are accepted",
+            total_size, MAX_ARCHIVE_SIZE
+        )));
+    }
+    Ok(())
+}
+// Regular files only, in a stable order. Symbolic links are skipped, they could point anywhere in
+// the DispVM.
+fn list_archive_members(directory: &Path, members: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(fs::DirEntry::path);
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            list_archive_members(&entry.path(), members)?;
+        } else if file_type.is_file() {
+            members.push(entry.path());
+        }
+    }
+    Ok(())
+}
+fn convert_archive(
+    temporary_directory_file: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
+    depth: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Start unpacking archive, depth {}", depth);
+    if depth >= MAX_ARCHIVE_DEPTH {
+        return Err(limit_exceeded(format!(
+            "Archives nested more than {} levels deep",
+            MAX_ARCHIVE_DEPTH
+        )));
+    }
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    check_archive_listing(&source_file)?;
+    let unpack_directory = PathBuf::from(format!("{}/unpacked", temporary_directory_file));
+    fs::create_dir_all(&unpack_directory)?;
+    // bsdtar refuses absolute paths and ".." by default.
+    let bsdtar_process = Command::new("bsdtar")
+        .args(&["-x", "--no-same-owner", "--no-same-permissions", "-f"])
+        .arg(&source_file)
+        .arg("-C")
+        .arg(&unpack_directory)
+        .output()?;
+    if !bsdtar_process.status.success() {
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "bsdtar failed: {}",
+                String::from_utf8_lossy(&bsdtar_process.stderr)
+            ),
+        )
+        .into());
+    }
+    let mut members = Vec::new();
+    list_archive_members(&unpack_directory, &mut members)?;
+    let members = members
+        .into_iter()
+        .map(|member| {
+            let path = member
+                .strip_prefix(&unpack_directory)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            (path, member)
+        })
+        .collect::<Vec<_>>();
+    check_members(&members)?;
+    send_message(&ServerMessage::FileInfo {
+        number_pages: u16::try_from(members.len())?,
+        output_type: OutputType::Archive,
+    })?;
+    convert_members(
+        temporary_directory_file,
+        &members,
+        passwords,
+        options,
+        depth + 1,
+    )
+}
+// Real sizes, once everything is on disk.
+fn check_members(members: &[(String, PathBuf)]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut total_size: u64 = 0;
+    for (_, member) in members {
+        total_size += fs::metadata(member)?.len();
+    }
+    if members.len() > MAX_ARCHIVE_MEMBERS || total_size > MAX_ARCHIVE_SIZE {
+        return Err(limit_exceeded("Unpacked archive larger than announced"));
+    }
+    // Progress is counted in members, an empty archive has nothing to show.
+    if members.is_empty() {
+        return Err(ConversionFailure::new(FailureReason::UnsupportedType, "Empty archive").into());
+    }
+    debug!("{} members, {} bytes", members.len(), total_size);
+    Ok(())
+}
+// Send every member as an `ArchiveMember` followed by its own conversion. `members` are the
+// name to show to the client and the file on disk.
+fn convert_members(
+    temporary_directory_file: &str,
+    members: &[(String, PathBuf)],
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
+    depth: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (member_id, (path, member)) in members.iter().enumerate() {
+        debug!("Archive member: {}", path);
+        send_message(&ServerMessage::ArchiveMember {
+            path: path.to_string(),
+        })?;
+        let member_directory = format!("{}/member_{}", temporary_directory_file, member_id);
+        fs::create_dir_all(&member_directory)?;
+        fs::rename(
+            member,
+            format!("{}/{}", member_directory, TO_CONVERT_FILENAME),
+        )?;
+        let extension = Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Err(e) = convert_file(&member_directory, &extension, passwords, options, depth) {
+            debug!("Member conversion failed: {}", e);
+            send_message(&ServerMessage::Failure(into_conversion_failure(e)))?;
+        }
+        fs::remove_dir_all(&member_directory)?;
+    }
+    Ok(())
+}
+
+const EMAIL_SCRIPT: &str = r"
+import email, email.policy, html.parser, os, sys
+
+class TextExtractor(html.parser.HTMLParser):
+    def __init__(self):
+        super().__init__()
+        self.text = []
+        self.skip = 0
+    def handle_starttag(self, tag, attrs):
+        if tag in ('script', 'style'):
+            self.skip += 1
+        elif tag in ('br', 'p', 'div', 'tr', 'li'):
+            self.text.append('\n')
+    def handle_endtag(self, tag):
+        if tag in ('script', 'style') and self.skip:
+            self.skip -= 1
+    def handle_data(self, data):
+        if not self.skip:
+            self.text.append(data)
+
+source, destination, max_attachments = sys.argv[1], sys.argv[2], int(sys.argv[3])
+with open(source, 'rb') as source_file:
+    message = email.message_from_binary_file(source_file, policy=email.policy.default)
+body = message.get_body(preferencelist=('plain', 'html'))
+
+lines = []
+for header in ('From', 'To', 'Cc', 'Date', 'Subject'):
+    for value in message.get_all(header, []):
+        lines.append('%s: %s' % (header, ' '.join(str(value).splitlines())))
+lines.append('')
+if body is not None:
+    try:
+        content = body.get_content()
+    except (LookupError, UnicodeError):
+        content = (body.get_payload(decode=True) or b'').decode('utf-8', 'replace')
+    if body.get_content_subtype() == 'html':
+        extractor = TextExtractor()
+        extractor.feed(content)
+        content = ''.join(extractor.text)
+    lines.append(content)
+
+attachments = []
+def collect(part):
+    if part is body:
+        return
+    if part.get_content_type() == 'message/rfc822':
+        attachments.append((part.get_filename() or 'message.eml', part.get_payload(0).as_bytes()))
+    elif part.is_multipart():
+        for subpart in part.iter_parts():
+            collect(subpart)
+    elif part.get_filename() is not None or part.get_content_maintype() != 'text':
+        attachments.append((part.get_filename() or 'attachment', part.get_payload(decode=True) or b''))
+if message.is_multipart():
+    for part in message.iter_parts():
+        collect(part)
+if len(attachments) > max_attachments:
+    sys.exit('%d attachments, at most %d are accepted' % (len(attachments), max_attachments))
+
+os.makedirs(os.path.join(destination, 'attachments'))
+with open(os.path.join(destination, 'message.txt'), 'w', encoding='utf-8') as message_file:
+    message_file.write('\n'.join(lines))
+with open(os.path.join(destination, 'names'), 'w', encoding='utf-8') as names:
+    for number, (name, content) in enumerate(attachments):
+        with open(os.path.join(destination, 'attachments', str(number)), 'wb') as attachment:
+            attachment.write(content)
+        names.write(' '.join(name.splitlines()) + '\n')
+";
+const MBOX_SCRIPT: &str = r"
+import mailbox, os, sys
+
+source, destination, max_messages = sys.argv[1], sys.argv[2], int(sys.argv[3])
+os.makedirs(destination)
+for number, message in enumerate(mailbox.mbox(source, create=False)):
+    if number >= max_messages:
+        sys.exit('More than %d messages' % max_messages)
+    with open(os.path.join(destination, '%05d.eml' % number), 'wb') as message_file:
+        message_file.write(message.as_bytes())
+";
+fn run_python_script(script: &str, args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+    let python_process = Command::new("python3")
+        .arg("-c")
+        .arg(script)
+        .args(args)
+        .output()?;
+    if !python_process.status.success() {
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "python failed: {}",
+                String::from_utf8_lossy(&python_process.stderr)
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+fn is_email(mimetype: &mime::Mime) -> bool {
+    mimetype.essence_str() == "message/rfc822"
+}
+fn is_mailbox(mimetype: &mime::Mime) -> bool {
+    mimetype.essence_str() == "application/mbox"
+}
+fn is_outlook_message(mimetype: &mime::Mime) -> bool {
+    mimetype.essence_str() == "application/vnd.ms-outlook"
+}
+// Headers and body are rendered as a text document, attachments are members.
+fn convert_email(
+    temporary_directory_file: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
+    depth: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Start converting email, depth {}", depth);
+    if depth >= MAX_ARCHIVE_DEPTH {
+        return Err(limit_exceeded(format!(
+            "Archives nested more than {} levels deep",
+            MAX_ARCHIVE_DEPTH
+        )));
+    }
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let extracted_directory = format!("{}/email", temporary_directory_file);
+    run_python_script(
+        EMAIL_SCRIPT,
+        &[
+            &source_file,
+            &extracted_directory,
+            &MAX_ARCHIVE_MEMBERS.to_string(),
+        ],
+    )?;
+    let attachments = fs::read_to_string(format!("{}/names", extracted_directory))?
+        .lines()
+        .enumerate()
+        .map(|(number, name)| {
+            (
+                name.to_string(),
+                PathBuf::from(format!("{}/attachments/{}", extracted_directory, number)),
+            )
+        })
+        .collect::<Vec<_>>();
+    if !attachments.is_empty() {
+        check_members(&attachments)?;
+    }
+    send_message(&ServerMessage::FileInfo {
+        number_pages: u16::try_from(attachments.len() + 1)?,
+        output_type: OutputType::Email,
+    })?;
+    let message_directory = format!("{}/message", temporary_directory_file);
+    fs::create_dir_all(&message_directory)?;
+    fs::rename(
+        format!("{}/message.txt", extracted_directory),
+        format!("{}/{}", message_directory, TO_CONVERT_FILENAME),
+    )?;
+    if let Err(e) = convert_text(&message_directory) {
+        debug!("Message rendering failed: {}", e);
+        send_message(&ServerMessage::Failure(into_conversion_failure(e)))?;
+    }
+    fs::remove_dir_all(&message_directory)?;
+    convert_members(
+        temporary_directory_file,
+        &attachments,
+        passwords,
+        options,
+        depth + 1,
+    )
+}
+fn convert_outlook_message(
+    temporary_directory_file: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
+    depth: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Start converting Outlook message to email");
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let email_file = format!("{}/message.eml", temporary_directory_file);
+    let msgconvert_process = Command::new("msgconvert")
+        .args(&["--outfile", &email_file, &source_file])
+        .output()?;
+    if !msgconvert_process.status.success() {
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "msgconvert failed: {}",
+                String::from_utf8_lossy(&msgconvert_process.stderr)
+            ),
+        )
+        .into());
+    }
+    fs::rename(&email_file, &source_file)?;
+    convert_email(temporary_directory_file, passwords, options, depth)
+}
+// A mailbox is an archive of emails.
+fn convert_mailbox(
+    temporary_directory_file: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
+    depth: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Start splitting mailbox, depth {}", depth);
+    if depth >= MAX_ARCHIVE_DEPTH {
+        return Err(limit_exceeded(format!(
+            "Archives nested more than {} levels deep",
+            MAX_ARCHIVE_DEPTH
+        )));
+    }
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let messages_directory = PathBuf::from(format!("{}/messages", temporary_directory_file));
+    run_python_script(
+        MBOX_SCRIPT,
+        &[
+            &source_file,
+            messages_directory.to_str().unwrap(),
+            &MAX_ARCHIVE_MEMBERS.to_string(),
+        ],
+    )?;
+    let mut messages = Vec::new();
+    list_archive_members(&messages_directory, &mut messages)?;
+    let messages = messages
+        .into_iter()
+        .enumerate()
+        .map(|(number, message)| (format!("message_{}.eml", number + 1), message))
+        .collect::<Vec<_>>();
+    check_members(&messages)?;
+    send_message(&ServerMessage::FileInfo {
+        number_pages: u16::try_from(messages.len())?,
+        output_type: OutputType::Archive,
+    })?;
+    convert_members(
+        temporary_directory_file,
+        &messages,
+        passwords,
+        options,
+        depth + 1,
+    )
+}
+// Pick the converter from the content of the file. The extension only helps for formats without
+// signature.
+fn convert_file(
+    temporary_directory_file: &str,
+    extension: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
+    depth: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_path = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let mimetype: mime::Mime = tree_magic::from_filepath(Path::new(&file_path))
+        .parse()
+        .map_err(|_| ConversionFailure::new(FailureReason::UnsupportedType, "Unknown mimetype"))?;
+    debug!("Mime found: {:?}", mimetype);
+    match (mimetype.type_(), mimetype.subtype()) {
+        (mime::AUDIO, _) => convert_audio(temporary_directory_file),
+        (mime::VIDEO, _) => convert_video(temporary_directory_file, options.video_audio),
+        (mime::IMAGE, _) => convert_image(temporary_directory_file, &file_path, options),
+        _ if is_html(&mimetype) => {
+            convert_html(temporary_directory_file, passwords, options, false)
+        }
+        _ if is_markdown(&mimetype, extension) => {
+            convert_html(temporary_directory_file, passwords, options, true)
+        }
+        (_, mime::PDF) => convert_pdf(temporary_directory_file, passwords, options),
+        _ if is_archive(&mimetype) => {
+            convert_archive(temporary_directory_file, passwords, options, depth)
+        }
+        _ if is_email(&mimetype) => {
+            convert_email(temporary_directory_file, passwords, options, depth)
+        }
+        _ if is_outlook_message(&mimetype) => {
+            convert_outlook_message(temporary_directory_file, passwords, options, depth)
+        }
+        _ if is_mailbox(&mimetype) => {
+            convert_mailbox(temporary_directory_file, passwords, options, depth)
+        }
+        _ if is_text(&mimetype) => convert_text(temporary_directory_file),
+        _ => convert_office(temporary_directory_file, passwords, options),
+    }
+}
+// Text bigger than this is better read in a text editor than in a PDF.
+const MAX_TEXT_SIZE: usize = 16 * 1024 * 1024;
+const TAB_WIDTH: usize = 8;
+
+fn is_text(mimetype: &mime::Mime) -> bool {
+    (mimetype.type_() == mime::TEXT && mimetype.subtype() != "rtf")
+        || matches!(
+            mimetype.essence_str(),
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-shellscript"
+                | "application/x-yaml"
+                | "application/toml"
+                | "application/sql"
+        )
+}
+fn push_wrapped(text: &mut String, column: &mut usize, c: char) {
+    if *column == protocol::MAX_TEXT_LINE_LENGTH {
+        text.push('\n');
+        *column = 0;
+    }
+    text.push(c);
+    *column += 1;
+}
+// Turn untrusted bytes into text accepted by `protocol::read_text`: invalid UTF-8 and forbidden
+// characters become U+FFFD, line endings become "\n", tabs become spaces and long lines are
+// wrapped.
+fn sanitize_text(content: &[u8]) -> String {
+    let content = String::from_utf8_lossy(content);
+    let content = content.strip_prefix('\u{FEFF}').unwrap_or(&content);
+    let mut text = String::with_capacity(content.len());
+    let mut column = 0;
+    let mut previous = '\0';
+    for c in content.chars() {
+        match c {
+            '\n' if previous == '\r' => {}
+            '\r' | '\n' => {
+                text.push('\n');
+                column = 0;
+            }
+            '\t' => {
+                for _ in 0..TAB_WIDTH - column % TAB_WIDTH {
+                    push_wrapped(&mut text, &mut column, ' ');
+                }
+            }
+            c if protocol::is_allowed_text_char(c) => push_wrapped(&mut text, &mut column, c),
+            _ => push_wrapped(&mut text, &mut column, '\u{FFFD}'),
+        }
+        previous = c;
+    }
+    text
+}
+// Only the text is sent, the client typesets it again.
+fn convert_text(temporary_directory_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Start converting text");
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let content = fs::read(&source_file)?;
+    if content.len() > MAX_TEXT_SIZE {
+        return Err(limit_exceeded(format!(
+            "{} bytes of text, at most {} are accepted",
+            content.len(),
+            MAX_TEXT_SIZE
+        )));
+    }
+    let text = sanitize_text(&content);
+    // Chunks end on a line boundary. A line is always far smaller than a chunk.
+    let mut chunks = vec![String::new()];
+    for line in text.split_inclusive('\n') {
+        if chunks.last().unwrap().len() + line.len() > protocol::MAX_TEXT_CHUNK_SIZE as usize {
+            chunks.push(String::new());
+        }
+        chunks.last_mut().unwrap().push_str(line);
+    }
+    send_message(&ServerMessage::FileInfo {
+        number_pages: u16::try_from(chunks.len())?,
+        output_type: OutputType::Text,
+    })?;
+    let mut stdout = io::stdout();
+    for chunk in chunks {
+        #[allow(clippy::cast_possible_truncation)]
+        ServerMessage::TextChunk {
+            length: chunk.len() as u32,
+        }
+        .write_to(&mut stdout)?;
+        stdout.write_all(chunk.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+// Remote resources, local files included, are never loaded: only inline "data:" URLs are.
+const HTML_SCRIPT: &str = r"
+import sys
+from weasyprint import CSS, HTML, default_url_fetcher
+
+source, destination, kind, layout = sys.argv[1:5]
+
+def offline_url_fetcher(url, *args, **kwargs):
+    if url.startswith('data:'):
+        return default_url_fetcher(url, *args, **kwargs)
+    raise ValueError('Blocked resource: %s' % url[:100])
+
+with open(source, 'rb') as source_file:
+    content = source_file.read()
+if kind == 'markdown':
+    import markdown
+    content = markdown.markdown(content.decode('utf-8', 'replace'), extensions=['extra'])
+# Consecutive pages without margin look like one long page once stacked.
+page_style = '@page { size: A4; margin: 0 }' if layout == 'single' else '@page { size: A4; margin: 15mm }'
+HTML(string=content, base_url='about:blank', url_fetcher=offline_url_fetcher).write_pdf(
+    destination, stylesheets=[CSS(string=page_style, url_fetcher=offline_url_fetcher)])
+";
+fn is_html(mimetype: &mime::Mime) -> bool {
+    matches!(
+        mimetype.essence_str(),
+        "text/html" | "application/xhtml+xml"
+    )
+}
+// Markdown has no signature, it is plain text with a ".md" name.
+fn is_markdown(mimetype: &mime::Mime, extension: &str) -> bool {
+    match mimetype.essence_str() {
+        "text/markdown" | "text/x-markdown" => true,
+        "text/plain" => matches!(extension.to_lowercase().as_str(), "md" | "markdown"),
+        _ => false,
+    }
+}
+fn convert_html(
+    temporary_directory_file: &str,
+    passwords: &PasswordPrompt,
+    options: &ConvertOptions,
+    markdown: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!(
+        "Start rendering {}",
+        if markdown { "Markdown" } else { "HTML" }
+    );
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let rendered_file = format!("{}/rendered.pdf", temporary_directory_file);
+    run_python_script(
+        HTML_SCRIPT,
+        &[
+            &source_file,
+            &rendered_file,
+            if markdown { "markdown" } else { "html" },
+            if options.html_single_page {
+                "single"
+            } else {
+                "pages"
+            },
+        ],
+    )?;
+    fs::rename(&rendered_file, &source_file)?;
+    if options.html_single_page {
+        convert_pdf_to_single_page(temporary_directory_file, options)
+    } else {
+        convert_pdf(temporary_directory_file, passwords, options)
+    }
+}
+// Stack every page of the PDF into one image.
+fn convert_pdf_to_single_page(
+    temporary_directory_file: &str,
+    options: &ConvertOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let source_file = format!("{}/{}", temporary_directory_file, TO_CONVERT_FILENAME);
+    let pdftocairo_process = Command::new("pdftocairo")
+        .args(&[
+            "-png",
+            "-r",
+            &options.dpi.to_string(),
+            &source_file,
+            "single",
+        ])
+        .current_dir(temporary_directory_file)
+        .output()?;
+    if !pdftocairo_process.status.success() {
+        return Err(ConversionFailure::new(
+            FailureReason::RendererCrash,
+            format!(
+                "pdftocairo failed: {}",
+                String::from_utf8_lossy(&pdftocairo_process.stderr)
+            ),
+        )
+        .into());
+    }
+    // pdftocairo pads the page numbers, so the names sort in page order.
+    let mut pages_name: Vec<PathBuf> =
+        glob::glob(&format!("{}/single-*.png", temporary_directory_file))
+            .expect("Failed to read glob pattern")
+            .collect::<Result<_, _>>()?;
+    pages_name.sort();
+    let mut sizes = Vec::new();
+    for page_name in &pages_name {
+        sizes.push(image::image_dimensions(page_name)?);
+    }
+    let width = sizes.iter().map(|&(width, _)| width).max().unwrap_or(0);
+    let height: u64 = sizes.iter().map(|&(_, height)| u64::from(height)).sum();
+    if width == 0 || height == 0 {
+        return Err(
+            ConversionFailure::new(FailureReason::RendererCrash, "Nothing rendered").into(),
+        );
+    }
+    // Each page is scaled down like `fit_page` would scale the whole stack, so that it never has
+    // to be held in memory at full size.
+    let scale = f64::min(
+        1.0,
+        f64::min(
+            f64::from(protocol::MAX_PAGE_WIDTH) / f64::from(width),
+            f64::from(protocol::MAX_PAGE_HEIGHT) / height as f64,
+        ),
+    );
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let scaled = |length: u32| ((f64::from(length) * scale).round() as u32).max(1);
+    let scaled_height: u32 = sizes.iter().map(|&(_, height)| scaled(height)).sum();
+    let mut single_page =
+        image::RgbaImage::from_pixel(scaled(width), scaled_height, image::Rgba([255; 4]));
+    let mut y = 0;
+    for (page_name, &(page_width, page_height)) in pages_name.iter().zip(&sizes) {
+        let mut page = image::open(page_name)?.into_rgba8();
+        if scale < 1.0 {
+            page = image::imageops::resize(
+                &page,
+                scaled(page_width),
+                scaled(page_height),
+                FilterType::Triangle,
+            );
+        }
+        single_page.copy_from(&page, 0, y)?;
+        y += page.height();
+    }
+    let single_page_file = format!("{}/single.png", temporary_directory_file);
+    single_page.save(&single_page_file)?;
+    send_message(&ServerMessage::FileInfo {
+        number_pages: 1,
+        output_type: OutputType::Image,
+    })?;
+    send_image(&single_page_file, options.pixel_format)
+}
+// Anything that is not already a `ConversionFailure` comes from a tool or a library choking on
+// the file.
+fn into_conversion_failure(error: Box<dyn std::error::Error>) -> ConversionFailure {
+    match error.downcast::<ConversionFailure>() {
+        Ok(failure) => *failure,
+        Err(error) => ConversionFailure::new(FailureReason::RendererCrash, error.to_string()),
+    }
+}
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     let stdin = io::stdin();
-    let temporary_directory = format!("/home/user/.temp_qubes_convert_{}", Uuid::new_v4());
+    // Always announce our own version first, so a mismatched client can report it.
+    protocol::send_hello(&mut io::stdout())?;
+    protocol::receive_hello(&mut stdin.lock())?;
+    let (batch_passwords, max_password_attempts, number_files, options) =
+        match ClientMessage::read_from(&mut stdin.lock())? {
+            ClientMessage::Batch {
+                passwords,
+                max_password_attempts,
+                number_files,
+                options,
+            } => (passwords, max_password_attempts, number_files, options),
+            _ => return Err(ProtocolError::InvalidValue("expected a batch header").into()),
+        };
+    let home = home::home_dir().ok_or("no home directory")?;
+    let temporary_directory = format!("{}/.temp_qubes_convert_{}", home.display(), Uuid::new_v4());
     fs::create_dir_all(&temporary_directory)?;
-    let default_password: String = stdin.lock().lines().next().unwrap()?;
-    let number_files: u16 = stdin.lock().lines().next().unwrap()?.parse()?;
     let (tx, rx) = channel();
+    let (password_tx, password_rx) = channel();
     let temporary_directory_clone = temporary_directory.clone();
-    thread::spawn(move || {
-        for file_id in 0..number_files {
+    // Files and password answers share stdin, so a single thread reads every frame and
+    // dispatches it. It stops at the end of stdin, once the conversions are gone, or at the first
+    // frame breaking the protocol.
+    let reader = thread::spawn(move || -> Result<(), ProtocolError> {
+        let mut file_id: u16 = 0;
+        loop {
+            let message = match ClientMessage::read_from(&mut stdin.lock()) {
+                Ok(message) => message,
+                Err(ProtocolError::Io(_)) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let (buffer, extension, file_passwords) = match message {
+                ClientMessage::File {
+                    content,
+                    extension,
+                    passwords,
+                } => (content, extension, passwords),
+                ClientMessage::Password { password } => {
+                    if password_tx.send(password).is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                ClientMessage::Batch { .. } => {
+                    return Err(ProtocolError::InvalidValue(
+                        "expected a file, received a batch header",
+                    ))
+                }
+            };
+            debug!("File received, size: {}", buffer.len());
             let temporary_directory_file = format!("{}/{}", &temporary_directory_clone, file_id);
-            fs::create_dir_all(&temporary_directory_file).unwrap();
-            let number_bytes: usize = stdin
-                .lock()
-                .lines()
-                .next()
-                .unwrap()
-                .unwrap()
-                .parse()
-                .unwrap();
-            debug!("Receiving file, size: {}", number_bytes);
-            let mut buffer = vec![0; number_bytes];
-            stdin.lock().read_exact(&mut buffer).unwrap();
-            debug!("File received");
+            fs::create_dir_all(&temporary_directory_file)?;
             let file_path = format!("{}/{}", &temporary_directory_file, TO_CONVERT_FILENAME);
-            let mut file = File::create(&file_path).unwrap();
-            file.write_all(&buffer).unwrap();
+            let mut file = File::create(&file_path)?;
+            file.write_all(&buffer)?;
             debug!("File written to disk");
-            let mimetype: mime::Mime = tree_magic::from_u8(&buffer)
-                .parse()
-                .expect("Incorrect detection of mimetype");
-            debug!("Mime found: {:?}", mimetype);
-            tx.send((temporary_directory_file, mimetype)).unwrap();
+            if tx
+                .send((file_id, temporary_directory_file, extension, file_passwords))
+                .is_err()
+            {
+                return Ok(());
+            }
+            file_id += 1;
         }
     });
-    for file_info in rx {
-        let temporary_directory_file = file_info.0;
-        let mimetype = file_info.1;
-        match (mimetype.type_(), mimetype.subtype()) {
-            (mime::AUDIO, _) => panic!("Audio convert not implemented"),
-            (mime::VIDEO, _) => panic!("Video convert not implemented"),
-            (mime::IMAGE, _) => convert_image(&format!(
-                "{}/{}",
-                &temporary_directory_file, &TO_CONVERT_FILENAME
-            ))?,
-            (_, mime::PDF) => convert_pdf(&temporary_directory_file, &default_password)?,
-            _ => convert_office(&temporary_directory_file, &default_password)?,
+    for _ in 0..number_files {
+        let (file_id, temporary_directory_file, extension, file_passwords) = match rx.recv() {
+            Ok(file) => file,
+            // The reader stopped before the last file: report why and clean up.
+            Err(_) => {
+                fs::remove_dir_all(&temporary_directory)?;
+                return Err(match reader.join() {
+                    Ok(Err(e)) => e,
+                    _ => ProtocolError::InvalidValue("stdin closed before the last file"),
+                }
+                .into());
+            }
+        };
+        let passwords = PasswordPrompt {
+            file_id,
+            candidates: file_passwords
+                .into_iter()
+                .chain(batch_passwords.iter().cloned())
+                .collect(),
+            max_attempts: max_password_attempts,
+            answers: &password_rx,
+        };
+        if let Err(e) = convert_file(
+            &temporary_directory_file,
+            &extension,
+            &passwords,
+            &options,
+            0,
+        ) {
+            debug!("Conversion failed: {}", e);
+            send_message(&ServerMessage::Failure(into_conversion_failure(e)))?;
         }
         fs::remove_dir_all(&temporary_directory_file)?;
     }